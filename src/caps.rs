@@ -0,0 +1,80 @@
+// IRCv3 client capabilities: what a client has asked us for with CAP REQ.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Cap {
+    // `time=` on relayed messages
+    ServerTime,
+    // tags at all: `msgid=` and client tags on relayed messages, and TAGMSG
+    MessageTags,
+    // the sender gets their own PRIVMSG/NOTICE/TAGMSG back once it's gone out
+    EchoMessage,
+    // BATCH, to group lines that go together
+    Batch,
+    // a `label` on a command comes back on its response
+    LabeledResponse,
+    // AWAY from people in rooms with us, when they go away or come back
+    AwayNotify,
+    // CHATHISTORY (which works without it, but this is how clients find out)
+    ChatHistory,
+    // ACCOUNT from people in rooms with us, when they log in or out
+    AccountNotify,
+    // JOINs that say who's logged in as what, and their realname
+    ExtendedJoin,
+    // `account=` on everything from someone who's logged in
+    AccountTag,
+    // AUTHENTICATE, to log in (which works without it, but this is how clients find out)
+    Sasl,
+}
+
+impl Cap {
+    // everything we offer in CAP LS, in the order we list it
+    pub const ALL: &'static [Cap] = &[
+        Cap::ServerTime, Cap::MessageTags, Cap::EchoMessage, Cap::Batch, Cap::LabeledResponse, Cap::ChatHistory, Cap::AwayNotify,
+        Cap::AccountNotify, Cap::ExtendedJoin, Cap::AccountTag, Cap::Sasl,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Cap::ServerTime => "server-time",
+            Cap::MessageTags => "message-tags",
+            Cap::EchoMessage => "echo-message",
+            Cap::Batch => "batch",
+            Cap::LabeledResponse => "labeled-response",
+            Cap::ChatHistory => "draft/chathistory",
+            Cap::AwayNotify => "away-notify",
+            Cap::AccountNotify => "account-notify",
+            Cap::ExtendedJoin => "extended-join",
+            Cap::AccountTag => "account-tag",
+            Cap::Sasl => "sasl",
+        }
+    }
+
+    pub fn from_name(name: &[u8]) -> Option<Cap> {
+        Cap::ALL.iter().copied().find(|c| c.name().as_bytes() == name)
+    }
+
+    fn bit(self) -> u32 {
+        1 << (self as u32)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct CapSet(u32);
+
+impl CapSet {
+    pub fn has(self, cap: Cap) -> bool {
+        self.0 & cap.bit() != 0
+    }
+
+    pub fn insert(&mut self, cap: Cap) {
+        self.0 |= cap.bit();
+    }
+
+    pub fn remove(&mut self, cap: Cap) {
+        self.0 &= !cap.bit();
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = Cap> {
+        Cap::ALL.iter().copied().filter(move |c| self.has(*c))
+    }
+}
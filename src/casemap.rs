@@ -0,0 +1,73 @@
+use unicode_normalization::UnicodeNormalization;
+
+use crate::protocol::IRCString;
+
+// How we decide that two nicks (or channel names) are "the same name".
+// Advertised to clients in ISUPPORT as CASEMAPPING.
+#[derive(Clone, Copy, Debug)]
+pub enum CaseMapping {
+    // only A-Z fold to a-z
+    Ascii,
+    // ASCII, plus []\~ fold to {}|^ (Scandinavian uppercase, supposedly)
+    Rfc1459,
+    // PRECIS: Unicode case folding with width mapping and NFC, for names that are valid UTF-8
+    Rfc7613,
+}
+
+// A name after case folding. Anything that's looked up by name should be keyed on one of these,
+// so `Alice` and `alice` can never belong to two different people.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Folded(Vec<u8>);
+
+impl Folded {
+    // (folding a folded name again doesn't change it, so this can be written
+    // out and read back in)
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl CaseMapping {
+    pub fn from_name(name: &str) -> Option<CaseMapping> {
+        [CaseMapping::Ascii, CaseMapping::Rfc1459, CaseMapping::Rfc7613].into_iter().find(|c| c.isupport_name() == name)
+    }
+
+    pub fn isupport_name(&self) -> &'static str {
+        match self {
+            CaseMapping::Ascii => "ascii",
+            CaseMapping::Rfc1459 => "rfc1459",
+            CaseMapping::Rfc7613 => "rfc7613",
+        }
+    }
+
+    pub fn fold(&self, name: &IRCString) -> Folded {
+        match self {
+            CaseMapping::Ascii => Folded(name.bytes.iter().map(|b| b.to_ascii_lowercase()).collect()),
+            CaseMapping::Rfc1459 => Folded(name.bytes.iter().map(|b| fold_rfc1459(*b)).collect()),
+            CaseMapping::Rfc7613 => match std::str::from_utf8(&name.bytes) {
+                Ok(s) => Folded(fold_precis(s).into_bytes()),
+                // not text, so there's no Unicode to fold: fall back to ASCII
+                Err(_) => CaseMapping::Ascii.fold(name),
+            }
+        }
+    }
+}
+
+fn fold_rfc1459(b: u8) -> u8 {
+    match b {
+        b'[' => b'{',
+        b']' => b'}',
+        b'\\' => b'|',
+        b'~' => b'^',
+        _ => b.to_ascii_lowercase()
+    }
+}
+
+fn fold_precis(s: &str) -> String {
+    // width mapping: fullwidth and halfwidth forms become their ordinary equivalents
+    let widened: String = s.chars().flat_map(|c| {
+        if ('\u{FF01}'..='\u{FFEE}').contains(&c) { c.to_string().nfkc().collect() } else { vec![c] }
+    }).collect();
+
+    widened.to_lowercase().nfc().collect()
+}
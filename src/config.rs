@@ -0,0 +1,431 @@
+use std::{collections::HashMap, fs, net::SocketAddr, path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use crate::{casemap::CaseMapping, encoding::InputEncoding};
+
+// Server-wide settings. Everything starts from Config::default(), and a
+// config file (see Config::load) can override some of it.
+pub struct Config {
+    pub server_name: String,
+    pub listeners: Vec<Listener>,
+    pub casemapping: CaseMapping,
+    pub names: NameRules,
+
+    pub classes: HashMap<String, Arc<ConnectionClass>>,
+    pub opers: Vec<OperBlock>,
+    pub accounts: Vec<AccountBlock>,
+
+    pub rooms: RoomConfig,
+    pub client_tags: ClientTags,
+    pub history: HistoryConfig,
+
+    // sent to everyone in an ERROR when we're asked to stop
+    pub shutdown_message: String,
+    // how long we wait for everyone's output to flush before exiting anyway
+    pub shutdown_timeout: Duration,
+}
+
+// Limits on names. See names.rs.
+pub struct NameRules {
+    pub nicklen: usize,
+    pub userlen: usize,
+    pub channellen: usize,
+    // the characters channel names can start with
+    pub chantypes: String,
+    // nicks nobody can take (compared after case folding)
+    pub reserved_nicks: Vec<String>,
+}
+
+// Where we accept connections, and which class they get.
+pub struct Listener {
+    pub addr: SocketAddr,
+    pub class: String,
+    pub encoding: InputEncoding,
+}
+
+// Credentials for the OPER command.
+pub struct OperBlock {
+    pub name: String,
+    pub password: String,
+}
+
+// An account to log in to with SASL PLAIN.
+pub struct AccountBlock {
+    pub name: String,
+    pub password: String,
+}
+
+// Per-connection limits and timers.
+pub struct ConnectionClass {
+    // how long a connection can be silent before we PING it
+    pub ping_interval: Duration,
+    // how long we wait for anything (usually PONG) after sending that PING
+    pub ping_timeout: Duration,
+    // how long a connection has to finish NICK/USER
+    pub registration_timeout: Duration,
+    // how many bytes of output can be waiting on a slow client before we give up on it
+    pub sendq: usize,
+    // how many messages from other users and rooms can be waiting on the connection's task
+    pub mailbox: usize,
+    // how many nicks a connection can MONITOR
+    pub monitor: usize,
+
+    pub flood: FloodControl,
+}
+
+// Command rate limiting. See flood::Throttle.
+pub struct FloodControl {
+    // how many commands a client can fire off back-to-back
+    pub burst: f32,
+    // how many commands per second it gets back after that (at 0, overdrawing it
+    // at all is Excess Flood)
+    pub refill: f32,
+    // extra cost of each target past the first on PRIVMSG/NOTICE/TAGMSG
+    pub per_target: f32,
+    // how far behind (fakelag) a client can fall before we drop it for Excess Flood
+    pub max_lag: Duration,
+    // skip rate limiting for everyone in this class (trusted bots)
+    pub exempt: bool,
+    // skip rate limiting for opers
+    pub exempt_opers: bool,
+}
+
+pub struct RoomConfig {
+    // how many messages a room buffers for members who are slow to pick them up
+    pub broadcast_capacity: usize,
+    // what happens to a member who falls further behind than that
+    pub lag_policy: LagPolicy,
+    // how many recent messages a room keeps around to resync lagging members from
+    pub resync_history: usize,
+    // how many messages from members can be waiting on the room's task
+    pub mailbox: usize,
+    // rooms that stay open even with nobody in them (the rest go away when they empty)
+    pub registered: Vec<String>,
+    // rooms that replay some history to whoever joins, for clients that can't
+    // ask for it with CHATHISTORY
+    pub playback: Vec<(String, Playback)>,
+}
+
+// How much a room replays on JOIN. (Never more than history.max_query messages.)
+#[derive(Clone, Copy)]
+pub enum Playback {
+    // the last this many messages
+    Messages(usize),
+    // whatever was said in the last this long
+    Within(Duration),
+}
+
+// What rooms and DM conversations remember, for CHATHISTORY. See history.rs.
+pub struct HistoryConfig {
+    // how many messages each room (or conversation) keeps
+    pub max_messages: usize,
+    // and for how long
+    pub max_age: Duration,
+    // the most messages one CHATHISTORY can ask for
+    pub max_query: usize,
+    // where history is saved at shutdown and loaded from at startup
+    // (without one, it's gone when the server is)
+    pub path: Option<PathBuf>,
+}
+
+// Which `+` client-only tags get passed along on PRIVMSG, NOTICE and TAGMSG.
+pub struct ClientTags {
+    pub filter: TagFilter,
+    // the most a client can send in one message, in bytes as they're written
+    // (without the @ and the space, and counting the ones we'd filter out)
+    pub max_bytes: usize,
+}
+
+// Tag names here are without their `+`, like `typing` or `draft/react`.
+pub enum TagFilter {
+    // only these
+    Allow(Vec<String>),
+    // anything but these
+    Deny(Vec<String>),
+}
+
+#[derive(Clone, Copy)]
+pub enum LagPolicy {
+    // drop them from the server with an error
+    Disconnect,
+    // tell them how much they missed and replay what we can
+    Resync,
+}
+
+impl Config {
+    // Config::default(), with whatever the file at `path` changes. One setting
+    // per line, and lines starting with `#` are comments:
+    //
+    //   server_name irc.example.org
+    //   casemapping ascii|rfc1459|rfc7613
+    //   nicklen|userlen|channellen <count>
+    //   class <name> <setting> <value>         (see ConnectionClass::set)
+    //   listener <addr:port> <class> [bytes|require-utf8|transcode-legacy]
+    //   oper <name> <password>
+    //   account <name> <password>
+    //   lag_policy disconnect|resync
+    //   broadcast_capacity <count>
+    //   registered_room <room>
+    //   playback <room> messages <count>
+    //   playback <room> within <seconds>
+    //   client_tags allow|deny <tag,tag,...>
+    //   client_tags_max_bytes <count>
+    //   history_path <path>
+    //   history_max_messages <count>
+    //   history_max_age <seconds>
+    //   shutdown_message <text>
+    //   shutdown_timeout <seconds>
+    //
+    // A class starts out as a copy of the default one, and each `class` line
+    // changes one setting. The first `listener` replaces the default one, and
+    // the rest add to it.
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut config = Config::default();
+        let mut listeners = vec![];
+
+        for (i, line) in text.lines().enumerate() {
+            // (a comment is a whole line: room names start with # too)
+            if line.trim_start().starts_with('#') { continue }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let bad = |what: &str| format!("{}:{}: {}", path.display(), i + 1, what);
+            match words.as_slice() {
+                [] => {}
+                ["server_name", name] => { config.server_name = name.to_string() }
+                ["casemapping", name] => {
+                    config.casemapping = CaseMapping::from_name(name).ok_or_else(|| bad("unknown casemapping"))?;
+                }
+                ["nicklen", n] => { config.names.nicklen = count(n).map_err(bad)? }
+                ["userlen", n] => { config.names.userlen = count(n).map_err(bad)? }
+                ["channellen", n] => { config.names.channellen = count(n).map_err(bad)? }
+                ["class", name, setting, value] => {
+                    let class = config.classes.entry(name.to_string()).or_default();
+                    // (nothing else has hold of it yet)
+                    Arc::get_mut(class).expect("class shared while loading").set(setting, value).map_err(bad)?;
+                }
+                ["listener", addr, class, rest @ ..] => {
+                    let addr = addr.parse().map_err(|_| bad("bad listener address"))?;
+                    let encoding = match rest {
+                        [] => InputEncoding::Bytes,
+                        [name] => InputEncoding::from_name(name).ok_or_else(|| bad("unknown encoding"))?,
+                        _ => return Err(bad("too many words"))
+                    };
+                    // (the class can come later in the file)
+                    listeners.push((bad("unknown connection class"), Listener { addr, class: class.to_string(), encoding }));
+                }
+                ["oper", name, password] => {
+                    config.opers.push(OperBlock { name: name.to_string(), password: password.to_string() });
+                }
+                ["account", name, password] => {
+                    config.accounts.push(AccountBlock { name: name.to_string(), password: password.to_string() });
+                }
+                ["lag_policy", name] => {
+                    config.rooms.lag_policy = LagPolicy::from_name(name).ok_or_else(|| bad("unknown lag policy"))?;
+                }
+                ["broadcast_capacity", n] => { config.rooms.broadcast_capacity = count(n).map_err(bad)? }
+                ["registered_room", room] => { config.rooms.registered.push(room.to_string()) }
+                ["playback", room, kind, n] => {
+                    let n: u64 = n.parse().map_err(|_| bad("bad number"))?;
+                    let playback = match *kind {
+                        "messages" => Playback::Messages(n as usize),
+                        "within" => Playback::Within(Duration::from_secs(n)),
+                        _ => return Err(bad("playback is `messages` or `within`"))
+                    };
+                    config.rooms.playback.push((room.to_string(), playback));
+                }
+                ["client_tags", kind, names] => {
+                    let names = names.split(',').filter(|n| !n.is_empty()).map(|n| n.to_string()).collect();
+                    config.client_tags.filter = match *kind {
+                        "allow" => TagFilter::Allow(names),
+                        "deny" => TagFilter::Deny(names),
+                        _ => return Err(bad("client_tags is `allow` or `deny`"))
+                    };
+                }
+                ["client_tags_max_bytes", n] => { config.client_tags.max_bytes = count(n).map_err(bad)? }
+                ["history_path", p] => { config.history.path = Some(PathBuf::from(p)) }
+                ["history_max_messages", n] => { config.history.max_messages = count(n).map_err(bad)? }
+                ["history_max_age", t] => { config.history.max_age = seconds(t).map_err(bad)? }
+                ["shutdown_message", ..] => {
+                    config.shutdown_message = line.trim()["shutdown_message".len()..].trim_start().to_string();
+                }
+                ["shutdown_timeout", t] => { config.shutdown_timeout = seconds(t).map_err(bad)? }
+                _ => return Err(bad("unknown setting"))
+            }
+        }
+
+        let mut chosen = vec![];
+        for (unknown_class, listener) in listeners {
+            if !config.classes.contains_key(&listener.class) { return Err(unknown_class) }
+            chosen.push(listener);
+        }
+        if !chosen.is_empty() { config.listeners = chosen; }
+        Ok(config)
+    }
+}
+
+impl ConnectionClass {
+    // One `class` line's worth:
+    //
+    //   ping_interval|ping_timeout|registration_timeout <seconds>
+    //   sendq <bytes>
+    //   mailbox <count>
+    //   monitor <count>
+    //   burst|refill|per_target <number>       (flood control)
+    //   max_lag <seconds>
+    //   exempt|exempt_opers yes|no
+    fn set(&mut self, setting: &str, value: &str) -> Result<(), &'static str> {
+        match setting {
+            "ping_interval" => self.ping_interval = seconds(value)?,
+            "ping_timeout" => self.ping_timeout = seconds(value)?,
+            "registration_timeout" => self.registration_timeout = seconds(value)?,
+            "sendq" => self.sendq = count(value)?,
+            "mailbox" => self.mailbox = count(value)?,
+            // (0 turns MONITOR off)
+            "monitor" => self.monitor = value.parse().map_err(|_| "bad number")?,
+            "burst" => self.flood.burst = rate(value)?,
+            "refill" => self.flood.refill = rate(value)?,
+            "per_target" => self.flood.per_target = rate(value)?,
+            "max_lag" => self.flood.max_lag = seconds(value)?,
+            "exempt" => self.flood.exempt = yes_no(value)?,
+            "exempt_opers" => self.flood.exempt_opers = yes_no(value)?,
+            _ => return Err("unknown class setting")
+        }
+        Ok(())
+    }
+}
+
+// Sizes and limits, none of which can be 0.
+fn count(value: &str) -> Result<usize, &'static str> {
+    match value.parse() {
+        Ok(0) => Err("must be more than 0"),
+        Ok(n) => Ok(n),
+        Err(_) => Err("bad number")
+    }
+}
+
+fn rate(value: &str) -> Result<f32, &'static str> {
+    match value.parse::<f32>() {
+        Ok(n) if n.is_finite() && n >= 0.0 => Ok(n),
+        _ => Err("bad number")
+    }
+}
+
+fn yes_no(value: &str) -> Result<bool, &'static str> {
+    match value {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("expected yes or no")
+    }
+}
+
+fn seconds(value: &str) -> Result<Duration, &'static str> {
+    value.parse().map(Duration::from_secs).map_err(|_| "bad number of seconds")
+}
+
+impl LagPolicy {
+    fn from_name(name: &str) -> Option<LagPolicy> {
+        match name {
+            "disconnect" => Some(LagPolicy::Disconnect),
+            "resync" => Some(LagPolicy::Resync),
+            _ => None
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server_name: "batircd.local".to_string(),
+            listeners: vec![
+                Listener { addr: "127.0.0.1:6667".parse().unwrap(), class: "default".to_string(), encoding: InputEncoding::Bytes },
+            ],
+            casemapping: CaseMapping::Rfc1459,
+            names: NameRules::default(),
+
+            classes: HashMap::from([
+                ("default".to_string(), Arc::new(ConnectionClass::default())),
+            ]),
+            opers: vec![],
+            accounts: vec![],
+
+            rooms: RoomConfig::default(),
+            client_tags: ClientTags::default(),
+            history: HistoryConfig::default(),
+
+            shutdown_message: "Server shutting down".to_string(),
+            shutdown_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Default for ConnectionClass {
+    fn default() -> Self {
+        ConnectionClass {
+            ping_interval: Duration::from_secs(120),
+            ping_timeout: Duration::from_secs(60),
+            registration_timeout: Duration::from_secs(30),
+            sendq: 1 << 20,
+            mailbox: 64,
+            monitor: 100,
+
+            flood: FloodControl::default(),
+        }
+    }
+}
+
+impl Default for FloodControl {
+    fn default() -> Self {
+        FloodControl {
+            burst: 10.0,
+            refill: 1.0,
+            per_target: 0.5,
+            max_lag: Duration::from_secs(30),
+            exempt: false,
+            exempt_opers: true,
+        }
+    }
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        RoomConfig {
+            broadcast_capacity: 256,
+            lag_policy: LagPolicy::Resync,
+            resync_history: 1024,
+            mailbox: 256,
+            registered: vec![],
+            playback: vec![],
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            max_messages: 1000,
+            max_age: Duration::from_secs(7 * 24 * 60 * 60),
+            max_query: 100,
+            path: None,
+        }
+    }
+}
+
+impl Default for ClientTags {
+    fn default() -> Self {
+        ClientTags {
+            filter: TagFilter::Deny(vec![]),
+            max_bytes: 4094,
+        }
+    }
+}
+
+impl Default for NameRules {
+    fn default() -> Self {
+        NameRules {
+            nicklen: 30,
+            userlen: 10,
+            channellen: 50,
+            chantypes: "#".to_string(),
+            reserved_nicks: vec!["NickServ".to_string(), "ChanServ".to_string()],
+        }
+    }
+}
@@ -4,39 +4,108 @@
 // NOTE: STD mutexes are not OK with async fns
 // DirectoryData should not expose any
 
-use std::{sync::{Arc, Mutex, Weak}, collections::HashMap};
+// Everything on the hot path (who has this nick? where's their mailbox?)
+// goes through ShardedMaps. The SlotMaps that own the users and rooms are
+// behind plain mutexes, but they're only touched on create and drop.
 
-use slotmap::{SlotMap, SecondaryMap};
-use tokio::sync::mpsc;
+use std::{collections::HashSet, hash::Hash, sync::{Arc, Mutex, Weak}, time::{Duration, SystemTime}};
 
-use crate::{room::{RoomID, Room}, user::{UserID, User}, sock::Sock, protocol::{IRCString, ToUser}};
+use slotmap::SlotMap;
+use tokio::sync::{mpsc, watch};
+
+use crate::{room::{RoomID, Room, RoomSnapshot}, user::{UserID, User, Profile}, sock::Sock, protocol::{IRCString, ToUser, U2R, D2U}, config::{Config, ConnectionClass}, metrics::Metrics, history::{History, Owner}, casemap::{CaseMapping, Folded}, encoding::InputEncoding, shard::ShardedMap};
 
 pub struct DirectoryRoot {
-    data: Arc<Mutex<DirectoryData>>,
+    data: Arc<DirectoryData>,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+    history: Arc<History>,
+    // for 003 RPL_CREATED
+    started: SystemTime,
+
+    // every Directory holds a clone of `running`, so `stopped` closes once they're all gone
+    running: mpsc::Sender<()>,
+    stopped: mpsc::Receiver<()>,
 }
 
 #[derive(Clone)]
 pub struct Directory {
-    data: Weak<Mutex<DirectoryData>>,
+    data: Weak<DirectoryData>,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+    history: Arc<History>,
+    started: SystemTime,
+    _running: mpsc::Sender<()>,
 }
 
 struct DirectoryData {
-    rooms: SlotMap<RoomID, Room>,
-    users: SlotMap<UserID, User>,
+    casemapping: CaseMapping,
+
+    rooms: Mutex<SlotMap<RoomID, Room>>,
+    users: Mutex<SlotMap<UserID, User>>,
+
+    user_mailboxes: ShardedMap<UserID, mpsc::Sender<ToUser>>,
+    room_mailboxes: ShardedMap<RoomID, mpsc::Sender<U2R>>,
 
-    users_by_nick: HashMap<IRCString, UserID>,
-    user_nicks: SecondaryMap<UserID, IRCString>,
+    // what anyone can find out about a user (once they've registered), or a room
+    user_profiles: ShardedMap<UserID, Arc<Profile>>,
+    room_snapshots: ShardedMap<RoomID, watch::Receiver<RoomSnapshot>>,
+
+    // names are looked up folded, but remembered the way their owners typed them
+    users_by_nick: ShardedMap<Folded, UserID>,
+    user_nicks: ShardedMap<UserID, IRCString>,
+    rooms_by_name: ShardedMap<Folded, RoomID>,
+    room_names: ShardedMap<RoomID, IRCString>,
+
+    // for MONITOR: who to tell when someone takes or lets go of a nick, and
+    // which nicks each user is watching, so they can be taken off when they go
+    watchers: ShardedMap<Folded, HashSet<UserID>>,
+    watching: ShardedMap<UserID, HashSet<Folded>>,
+
+    // who's in (or on their way into) which rooms, as each user's task sees
+    // it, so whoever cleans up after a crash knows who to tell
+    user_rooms: ShardedMap<UserID, HashSet<RoomID>>,
+    room_users: ShardedMap<RoomID, HashSet<UserID>>,
 }
 
 impl DirectoryRoot {
-    pub fn new() -> Self {
+    pub fn new(config: Arc<Config>) -> Self {
+        let (running, stopped) = mpsc::channel(1);
         Self { 
-            data: Arc::new(Mutex::new(DirectoryData::new()))
+            data: Arc::new(DirectoryData::new(config.casemapping)),
+            metrics: Arc::new(Metrics::default()),
+            history: Arc::new(History::new(config.casemapping, &config.history)),
+            config,
+            started: SystemTime::now(),
+
+            running,
+            stopped,
         }
     }
 
     pub fn share(&self) -> Directory {
-        Directory { data: Arc::downgrade(&self.data) }
+        Directory { 
+            data: Arc::downgrade(&self.data), 
+            config: self.config.clone(), 
+            metrics: self.metrics.clone(),
+            history: self.history.clone(),
+            started: self.started,
+            _running: self.running.clone(),
+        }
+    }
+
+    // (History outlives the Directory, so it can be saved once everyone's gone.)
+    pub fn history(&self) -> Arc<History> {
+        self.history.clone()
+    }
+
+    // Drop every user and room (which cancels them), then give their tasks up
+    // to `timeout` to say goodbye and flush. Returns false if some didn't make it.
+    pub async fn shutdown(self, timeout: Duration) -> bool {
+        let DirectoryRoot { data, running, mut stopped, .. } = self;
+        drop(running);
+        drop(data);
+        tokio::time::timeout(timeout, stopped.recv()).await.is_ok()
     }
 }
 
@@ -45,101 +114,434 @@ pub enum ChangeNickError {
 }
 
 impl Directory {
-    pub fn user_create(&self, conn: Sock) -> Option<UserID> {
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+
+    pub fn user_create(&self, conn: Sock, class: Arc<ConnectionClass>, encoding: InputEncoding) -> Option<UserID> {
         let dir = self.clone();
-        self.data.upgrade().map(|a| a.lock().unwrap().user_create(dir, conn))
+        self.data.upgrade().map(|a| a.user_create(dir, conn, class, encoding))
     }
 
     pub fn user_drop(&self, user_id: UserID) {
-        self.data.upgrade().map(|a| a.lock().unwrap().user_drop(user_id));
+        if let Some(a) = self.data.upgrade() { a.user_drop(user_id) }
+        // (nobody else can ever read their side of a conversation)
+        self.history.forget(&Owner::Connection(user_id));
     }
 
     pub fn user_get_mailbox(&self, user_id: UserID) -> Option<mpsc::Sender<ToUser>> {
-        self.data.upgrade().and_then(|a| a.lock().unwrap().user_get_mailbox(user_id))
+        self.data.upgrade().and_then(|a| a.user_get_mailbox(user_id))
     }
 
     pub fn user_by_nick(&self, nick: &IRCString) -> Option<UserID> {
-        self.data.upgrade().and_then(|a| a.lock().unwrap().user_by_nick(nick))
+        self.data.upgrade().and_then(|a| a.user_by_nick(nick))
     }
 
     pub fn user_get_nick(&self, user_id: UserID) -> Option<IRCString> {
-        self.data.upgrade().and_then(|a| a.lock().unwrap().user_get_nick(user_id))
+        self.data.upgrade().and_then(|a| a.user_get_nick(user_id))
     }
 
-    pub fn user_nick_to_mailbox(&self, nick: &IRCString) -> Option<mpsc::Sender<ToUser>> {
-        self.data.upgrade().and_then(|a| {
-            let a2 = a.lock().unwrap();
-            if let Some(user_id) = a2.user_by_nick(nick) {
-                a2.user_get_mailbox(user_id)
-            } else {
-                None
-            }
-        })
+    pub fn user_get_profile(&self, user_id: UserID) -> Option<Arc<Profile>> {
+        self.data.upgrade().and_then(|a| a.user_profiles.get(&user_id))
+    }
+
+    // Only the user's own task does this.
+    pub fn user_set_profile(&self, user_id: UserID, profile: Profile) {
+        if let Some(a) = self.data.upgrade() { a.user_profiles.insert(user_id, Arc::new(profile)); }
     }
 
     pub fn user_change_nick(&self, user_id: UserID, nick: Option<IRCString>) -> Result<(), ChangeNickError> {
         if let Some(dir) = self.data.upgrade() {
-            dir.lock().unwrap().user_change_nick(user_id, nick)
+            dir.user_change_nick(user_id, nick)
         } else {
             // doesn't matter
-            return Ok(())
+            Ok(())
         }
     }
+
+    // MONITOR `nick`, or stop.
+    pub fn user_watch(&self, user_id: UserID, nick: &IRCString) {
+        if let Some(a) = self.data.upgrade() { a.user_watch(user_id, nick) }
+    }
+
+    pub fn user_unwatch(&self, user_id: UserID, nick: &IRCString) {
+        if let Some(a) = self.data.upgrade() { a.user_unwatch(user_id, nick) }
+    }
+
+    // Only the user's own task does these: before it sends its Join, and once
+    // it's out (or the room is).
+    pub fn user_enter_room(&self, user_id: UserID, room_id: RoomID) {
+        if let Some(a) = self.data.upgrade() { a.user_enter_room(user_id, room_id) }
+    }
+
+    pub fn user_leave_room(&self, user_id: UserID, room_id: RoomID) {
+        if let Some(a) = self.data.upgrade() { a.user_leave_room(user_id, room_id) }
+    }
+
+    pub fn user_get_rooms(&self, user_id: UserID) -> Vec<RoomID> {
+        self.data.upgrade().and_then(|a| a.user_rooms.get(&user_id)).map(|r| r.into_iter().collect()).unwrap_or_default()
+    }
+
+    pub fn room_by_name(&self, name: &IRCString) -> Option<RoomID> {
+        self.data.upgrade().and_then(|a| a.room_by_name(name))
+    }
+
+    pub fn room_get_mailbox(&self, room_id: RoomID) -> Option<mpsc::Sender<U2R>> {
+        self.data.upgrade().and_then(|a| a.room_mailboxes.get(&room_id))
+    }
+
+    pub fn room_get_users(&self, room_id: RoomID) -> Vec<UserID> {
+        self.data.upgrade().and_then(|a| a.room_users.get(&room_id)).map(|u| u.into_iter().collect()).unwrap_or_default()
+    }
+
+    pub fn room_get_name(&self, room_id: RoomID) -> Option<IRCString> {
+        self.data.upgrade().and_then(|a| a.room_get_name(room_id))
+    }
+
+    pub fn room_get_snapshot(&self, room_id: RoomID) -> Option<RoomSnapshot> {
+        self.data.upgrade().and_then(|a| a.room_snapshots.get(&room_id)).map(|s| s.borrow().clone())
+    }
+
+    // Find the room with this name, making it if there isn't one.
+    // Returns its ID, its name as whoever made it typed it, and its mailbox.
+    pub fn room_get_or_create(&self, name: &IRCString) -> Option<(RoomID, IRCString, mpsc::Sender<U2R>)> {
+        let dir = self.clone();
+        self.data.upgrade().map(|a| a.room_get_or_create(dir, name))
+    }
+
+    pub fn room_reap(&self, room_id: RoomID) {
+        if let Some(a) = self.data.upgrade() { a.room_reap(room_id) }
+    }
 }
 
 impl DirectoryData {
-    fn new() -> Self {
+    fn new(casemapping: CaseMapping) -> Self {
         Self {
-            rooms: SlotMap::with_key(),
-            users: SlotMap::with_key(),
+            casemapping,
 
-            users_by_nick: HashMap::new(),
-            user_nicks: SecondaryMap::new(),
+            rooms: Mutex::new(SlotMap::with_key()),
+            users: Mutex::new(SlotMap::with_key()),
+
+            user_mailboxes: ShardedMap::new(),
+            room_mailboxes: ShardedMap::new(),
+
+            user_profiles: ShardedMap::new(),
+            room_snapshots: ShardedMap::new(),
+
+            users_by_nick: ShardedMap::new(),
+            user_nicks: ShardedMap::new(),
+            rooms_by_name: ShardedMap::new(),
+            room_names: ShardedMap::new(),
+
+            watchers: ShardedMap::new(),
+            watching: ShardedMap::new(),
+
+            user_rooms: ShardedMap::new(),
+            room_users: ShardedMap::new(),
         }
     }
 
-    fn user_create(&mut self, dir: Directory, conn: Sock) -> UserID {
-        self.users.insert_with_key(|uid| User::new(uid, conn, dir))
+    fn user_create(&self, dir: Directory, conn: Sock, class: Arc<ConnectionClass>, encoding: InputEncoding) -> UserID {
+        let mut users = self.users.lock().unwrap();
+        let user_id = users.insert_with_key(|uid| User::new(uid, conn, class, encoding, dir));
+        self.user_mailboxes.insert(user_id, users[user_id].get_mailbox());
+        user_id
     }
 
-    fn user_drop(&mut self, user_id: UserID) {
-        self.users.remove(user_id);
+    fn user_drop(&self, user_id: UserID) {
+        // release the nick so someone else can have it
+        if let Some(n) = self.user_nicks.remove(&user_id) {
+            self.release_nick(user_id, n);
+        }
+        for key in self.watching.remove(&user_id).unwrap_or_default() {
+            self.watchers.update(key, |w| without(w, &user_id));
+        }
+        for room_id in self.user_rooms.remove(&user_id).unwrap_or_default() {
+            self.room_users.update(room_id, |u| without(u, &user_id));
+        }
+        self.user_mailboxes.remove(&user_id);
+        self.user_profiles.remove(&user_id);
+        let user = self.users.lock().unwrap().remove(user_id);
+        drop(user);  // cancels them, if they weren't already on their way out
     }
 
     fn user_get_mailbox(&self, user_id: UserID) -> Option<mpsc::Sender<ToUser>> {
-        self.users.get(user_id).map(|x| x.get_mailbox())
+        self.user_mailboxes.get(&user_id)
     }
 
     fn user_by_nick(&self, nick: &IRCString) -> Option<UserID> {
-        self.users_by_nick.get(nick).map(|x| *x)
+        self.users_by_nick.get(&self.casemapping.fold(nick))
     }
 
     fn user_get_nick(&self, user_id: UserID) -> Option<IRCString> {
-        self.user_nicks.get(user_id).map(|x| x.clone())
+        self.user_nicks.get(&user_id)
     }
 
-    fn user_change_nick(&mut self, user_id: UserID, new_nick: Option<IRCString>) -> Result<(), ChangeNickError> {
+    fn user_change_nick(&self, user_id: UserID, new_nick: Option<IRCString>) -> Result<(), ChangeNickError> {
+        // only the user's own task changes its nick, so this can't race with itself
+        let old_nick = self.user_nicks.get(&user_id);
+
         // make sure this is needed
-        if self.user_nicks.get(user_id) == new_nick.as_ref() { return Ok(()) }
+        if old_nick == new_nick { return Ok(()) }
 
-        // figure out if we can get the new nick
-        if let Some(n) = new_nick.as_ref() {
-            if self.users_by_nick.contains_key(n) { return Err(ChangeNickError::NickInUse); }
+        // claim the new nick
+        // (it's fine if it's ours already: that's just a change of case, which
+        // isn't news)
+        let new_key = new_nick.as_ref().map(|n| self.casemapping.fold(n));
+        if let (Some(n), Some(k)) = (new_nick.as_ref(), new_key.as_ref()) {
+            let mut taken = false;
+            self.users_by_nick.update(k.clone(), |owner| match owner {
+                Some(owner) => { taken = owner != user_id; Some(owner) }
+                None => {
+                    self.notify_watchers(k, D2U::Online { user: user_id, nick: n.clone() });
+                    Some(user_id)
+                }
+            });
+            if taken { return Err(ChangeNickError::NickInUse) }
         }
 
-        // remove the user's old nick, if applicable
-        let old_nick = self.user_nicks.remove(user_id);
+        // let go of the old one, if applicable
         if let Some(n) = old_nick {
-            assert_eq!(Some(user_id), self.users_by_nick.remove(&n));
-            assert_eq!(Some(n), self.user_nicks.remove(user_id));
+            if Some(self.casemapping.fold(&n)) != new_key { self.release_nick(user_id, n) }
         }
 
-        // use the new nick
-        if let Some(n) = new_nick {
-            assert_eq!(None, self.user_nicks.insert(user_id, n.clone()));
-            assert_eq!(None, self.users_by_nick.insert(n, user_id));
+        match new_nick {
+            Some(n) => { self.user_nicks.insert(user_id, n); }
+            None => { self.user_nicks.remove(&user_id); }
         }
-
         Ok(())
     }
-}
\ No newline at end of file
+
+    // (if it's still ours)
+    fn release_nick(&self, user_id: UserID, nick: IRCString) {
+        let key = self.casemapping.fold(&nick);
+        self.users_by_nick.update(key.clone(), |owner| match owner {
+            Some(owner) if owner == user_id => {
+                self.notify_watchers(&key, D2U::Offline { nick });
+                None
+            }
+            other => other
+        });
+    }
+
+    fn user_enter_room(&self, user_id: UserID, room_id: RoomID) {
+        self.user_rooms.update(user_id, |r| with(r, room_id));
+        self.room_users.update(room_id, |u| with(u, user_id));
+    }
+
+    fn user_leave_room(&self, user_id: UserID, room_id: RoomID) {
+        self.user_rooms.update(user_id, |r| without(r, &room_id));
+        self.room_users.update(room_id, |u| without(u, &user_id));
+    }
+
+    fn user_watch(&self, user_id: UserID, nick: &IRCString) {
+        let key = self.casemapping.fold(nick);
+        self.watching.update(user_id, |w| with(w, key.clone()));
+        self.watchers.update(key, |w| with(w, user_id));
+    }
+
+    fn user_unwatch(&self, user_id: UserID, nick: &IRCString) {
+        let key = self.casemapping.fold(nick);
+        self.watchers.update(key.clone(), |w| without(w, &user_id));
+        self.watching.update(user_id, |w| without(w, &key));
+    }
+
+    // Tell everyone watching `key`. Call this while holding `key`'s shard of
+    // users_by_nick, so everything said about one nick arrives in the order
+    // it happened. (So nobody waits on it, either: a watcher whose mailbox is
+    // full misses it, and can catch up with MONITOR S.)
+    fn notify_watchers(&self, key: &Folded, message: D2U) {
+        for user_id in self.watchers.get(key).unwrap_or_default() {
+            if let Some(mailbox) = self.user_mailboxes.get(&user_id) {
+                let _ = mailbox.try_send(ToUser::Directory { message: message.clone() });
+            }
+        }
+    }
+
+    fn room_by_name(&self, name: &IRCString) -> Option<RoomID> {
+        self.rooms_by_name.get(&self.casemapping.fold(name))
+    }
+
+    fn room_get_name(&self, room_id: RoomID) -> Option<IRCString> {
+        self.room_names.get(&room_id)
+    }
+
+    fn room_lookup(&self, key: &Folded) -> Option<(RoomID, IRCString, mpsc::Sender<U2R>)> {
+        let room_id = self.rooms_by_name.get(key)?;
+        // (these can go missing if it's being reaped as we speak)
+        Some((room_id, self.room_names.get(&room_id)?, self.room_mailboxes.get(&room_id)?))
+    }
+
+    fn room_get_or_create(&self, dir: Directory, name: &IRCString) -> (RoomID, IRCString, mpsc::Sender<U2R>) {
+        let key = self.casemapping.fold(name);
+        if let Some(found) = self.room_lookup(&key) { return found }
+
+        // creating and reaping both happen under this lock, so nobody makes the
+        // same room twice, and nobody makes one that's halfway through being reaped
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some(found) = self.room_lookup(&key) { return found }
+
+        let is_key = |r: &String| self.casemapping.fold(&IRCString::from(r.as_str())) == key;
+        let registered = dir.config().rooms.registered.iter().any(is_key);
+        let playback = dir.config().rooms.playback.iter().find(|(r, _)| is_key(r)).map(|(_, p)| *p);
+        let room_id = rooms.insert_with_key(|rid| Room::new(rid, name.clone(), registered, playback, dir));
+        let mailbox = rooms[room_id].get_mailbox();
+        self.room_snapshots.insert(room_id, rooms[room_id].get_snapshot());
+
+        // by name last, so whoever finds it by name finds the rest too
+        self.room_mailboxes.insert(room_id, mailbox.clone());
+        self.room_names.insert(room_id, name.clone());
+        self.rooms_by_name.insert(key, room_id);
+        (room_id, name.clone(), mailbox)
+    }
+
+    fn room_reap(&self, room_id: RoomID) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some(n) = self.room_names.remove(&room_id) {
+            self.rooms_by_name.remove_if(&self.casemapping.fold(&n), |owner| *owner == room_id);
+        }
+        for user_id in self.room_users.remove(&room_id).unwrap_or_default() {
+            self.user_rooms.update(user_id, |r| without(r, &room_id));
+        }
+        self.room_mailboxes.remove(&room_id);
+        self.room_snapshots.remove(&room_id);
+        let room = rooms.remove(room_id);
+        drop(room);
+    }
+}
+
+// For the sets in ShardedMap::update: none at all is the same as an empty one.
+fn with<T: Hash + Eq>(set: Option<HashSet<T>>, item: T) -> Option<HashSet<T>> {
+    let mut set = set.unwrap_or_default();
+    set.insert(item);
+    Some(set)
+}
+
+fn without<T: Hash + Eq>(set: Option<HashSet<T>>, item: &T) -> Option<HashSet<T>> {
+    let mut set = set?;
+    set.remove(item);
+    if set.is_empty() { None } else { Some(set) }
+}
+
+#[cfg(test)]
+mod bench {
+    // Run with: cargo test --release -- --ignored --nocapture bench_
+    //
+    // The directory side of a PRIVMSG storm: thousands of tasks resolving nicks
+    // and room names to mailboxes at once, with a trickle of nick changes mixed
+    // in. Runs the same calls again behind one global lock, which is what the
+    // directory was before it was sharded.
+
+    use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+    use slotmap::SlotMap;
+    use tokio::sync::mpsc;
+
+    use crate::{config::Config, protocol::IRCString, room::RoomID, user::UserID};
+
+    use super::{Directory, DirectoryRoot};
+
+    const USERS: usize = 10_000;
+    const ROOMS: usize = 1_000;
+    const TASKS: usize = 4_000;
+    const LOOKUPS_PER_TASK: usize = 2_000;
+
+    fn nick(i: usize) -> IRCString { IRCString::from(format!("Nick{}", i).as_str()) }
+    fn room(i: usize) -> IRCString { IRCString::from(format!("#Room{}", i).as_str()) }
+
+    // Users and rooms with nothing behind their mailboxes, filed straight
+    // into the maps.
+    fn populate(root: &DirectoryRoot) -> Vec<UserID> {
+        let data = &root.data;
+        let mut user_ids: SlotMap<UserID, ()> = SlotMap::with_key();
+        let mut room_ids: SlotMap<RoomID, ()> = SlotMap::with_key();
+        let users: Vec<UserID> = (0..USERS).map(|i| {
+            let user_id = user_ids.insert(());
+            data.user_mailboxes.insert(user_id, mpsc::channel(1).0);
+            assert!(data.user_change_nick(user_id, Some(nick(i))).is_ok());
+            user_id
+        }).collect();
+        for i in 0..ROOMS {
+            let room_id = room_ids.insert(());
+            data.room_mailboxes.insert(room_id, mpsc::channel(1).0);
+            data.room_names.insert(room_id, room(i));
+            data.rooms_by_name.insert(data.casemapping.fold(&room(i)), room_id);
+        }
+        users
+    }
+
+    fn run(global: Option<Arc<Mutex<()>>>) -> Duration {
+        let root = DirectoryRoot::new(Arc::new(Config::default()));
+        let users = populate(&root);
+
+        let rt = tokio::runtime::Builder::new_multi_thread().build().unwrap();
+        rt.block_on(async {
+            let start = Instant::now();
+            let tasks: Vec<_> = (0..TASKS).map(|t| {
+                let dir: Directory = root.share();
+                let global = global.clone();
+                // each task is the only one to rename its own user, like a UserState
+                let (me, my_nick) = (users[t], nick(t));
+                tokio::spawn(async move {
+                    let lock = || global.as_ref().map(|g| g.lock().unwrap());
+                    let mut found = 0;
+                    for i in 0..LOOKUPS_PER_TASK {
+                        if i % 500 == 250 {
+                            let _held = lock();
+                            let away = IRCString::from(format!("{}|away", my_nick).as_str());
+                            assert!(dir.user_change_nick(me, Some(away)).is_ok());
+                        }
+                        if i % 500 == 499 {
+                            let _held = lock();
+                            assert!(dir.user_change_nick(me, Some(my_nick.clone())).is_ok());
+                        }
+
+                        let k = (t * 7919 + i * 104729) % USERS;
+                        {
+                            let _held = lock();
+                            // (this misses while they're away)
+                            if let Some(user_id) = dir.user_by_nick(&nick(k)) {
+                                if dir.user_get_mailbox(user_id).is_some() { found += 1 }
+                            }
+                        }
+                        {
+                            let _held = lock();
+                            let room_id = dir.room_by_name(&room(k % ROOMS)).unwrap();
+                            assert!(dir.room_get_mailbox(room_id).is_some());
+                        }
+                        if i % 64 == 0 { tokio::task::yield_now().await; }
+                    }
+                    found
+                })
+            }).collect();
+            let mut found = 0;
+            for t in tasks { found += t.await.unwrap(); }
+            assert!(found > TASKS * LOOKUPS_PER_TASK / 2);
+            start.elapsed()
+        })
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_concurrent_directory_lookups() {
+        let global = run(Some(Arc::new(Mutex::new(()))));
+        let sharded = run(None);
+        println!(
+            "{} tasks x {} nick and room lookups: global lock {:?}, sharded {:?} ({:.1}x)",
+            TASKS, LOOKUPS_PER_TASK, global, sharded,
+            global.as_secs_f64() / sharded.as_secs_f64()
+        );
+    }
+}
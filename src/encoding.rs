@@ -0,0 +1,52 @@
+// What we do with incoming lines that aren't valid UTF-8. Set per listener.
+#[derive(Clone, Copy, Debug)]
+pub enum InputEncoding {
+    // pass the bytes along untouched, whatever they are
+    Bytes,
+    // refuse anything that isn't UTF-8 (and say so in ISUPPORT with UTF8ONLY)
+    RequireUtf8,
+    // assume anything that isn't UTF-8 is CP1252 (which covers Latin-1) and convert it
+    TranscodeLegacy,
+}
+
+impl InputEncoding {
+    // (as it's written in the config file)
+    pub fn from_name(name: &str) -> Option<InputEncoding> {
+        match name {
+            "bytes" => Some(InputEncoding::Bytes),
+            "require-utf8" => Some(InputEncoding::RequireUtf8),
+            "transcode-legacy" => Some(InputEncoding::TranscodeLegacy),
+            _ => None
+        }
+    }
+}
+
+// Converts `line` in place. Returns false if it should be rejected instead.
+pub fn decode(encoding: InputEncoding, line: &mut Vec<u8>) -> bool {
+    let is_utf8 = std::str::from_utf8(line).is_ok();
+    match encoding {
+        InputEncoding::Bytes => true,
+        InputEncoding::RequireUtf8 => is_utf8,
+        InputEncoding::TranscodeLegacy => {
+            // a line is one encoding or the other: nobody mixes them within a message
+            if !is_utf8 { *line = cp1252_to_utf8(line).into_bytes(); }
+            true
+        }
+    }
+}
+
+fn cp1252_to_utf8(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| match b {
+        0x80..=0x9f => CP1252_HIGH[(b - 0x80) as usize],
+        // the rest of CP1252 lines up with Latin-1, which lines up with the first 256 code points
+        _ => b as char,
+    }).collect()
+}
+
+// CP1252's replacements for the C1 controls. The five it leaves undefined stay as they are.
+const CP1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
@@ -0,0 +1,81 @@
+use std::{collections::VecDeque, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::{config::FloodControl, protocol::Command};
+
+// A token bucket with a queue in front of it.
+//
+// Commands that the bucket can pay for run right away. Commands that overdraw
+// it are held back until the bucket would have refilled (fakelag), so a
+// flooding client sees its commands slow down instead of being dropped.
+pub struct Throttle {
+    tokens: f32,
+    last_refill: Instant,
+    // (when it's due, when it came in, the command)
+    queue: VecDeque<(Instant, Instant, Command)>,
+}
+
+pub struct ExcessFlood;
+
+impl Throttle {
+    pub fn new(fc: &FloodControl, now: Instant) -> Self {
+        Throttle {
+            tokens: fc.burst,
+            last_refill: now,
+            queue: VecDeque::new(),
+        }
+    }
+
+    fn refill(&mut self, fc: &FloodControl, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * fc.refill).min(fc.burst);
+        self.last_refill = now;
+    }
+
+    // Charge for `cmd` and queue it. It comes back out of pop_due once it's paid for.
+    pub fn push(&mut self, fc: &FloodControl, cmd: Command, now: Instant) -> Result<(), ExcessFlood> {
+        self.refill(fc, now);
+        self.tokens -= command_cost(fc, &cmd);
+
+        let lag = if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            // (and if it can't ever be paid back, because the bucket doesn't
+            // refill or not in any time we can count, that's too long too)
+            match Duration::try_from_secs_f32(-self.tokens / fc.refill) {
+                Ok(lag) => lag,
+                Err(_) => return Err(ExcessFlood)
+            }
+        };
+        if lag > fc.max_lag { return Err(ExcessFlood) }
+
+        self.queue.push_back((now + lag, now, cmd));
+        Ok(())
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.queue.front().map(|(due, _, _)| *due)
+    }
+
+    // Returns the command along with when it came in.
+    pub fn pop_due(&mut self, now: Instant) -> Option<(Instant, Command)> {
+        match self.queue.front() {
+            Some((due, _, _)) if *due <= now => self.queue.pop_front().map(|(_, received, cmd)| (received, cmd)),
+            _ => None
+        }
+    }
+}
+
+fn command_cost(fc: &FloodControl, cmd: &Command) -> f32 {
+    match cmd.cmd.bytes.as_slice() {
+        b"PRIVMSG" | b"NOTICE" | b"TAGMSG" => {
+            // each extra target is another message we have to deliver
+            let n_targets = cmd.args.first()
+                .map(|t| t.bytes.split(|b| *b == b',').filter(|t| !t.is_empty()).count())
+                .unwrap_or(1);
+            1.0 + fc.per_target * (n_targets.max(1) - 1) as f32
+        }
+        _ => 1.0
+    }
+}
@@ -0,0 +1,485 @@
+// What's been said in each room and DM conversation, for CHATHISTORY.
+//
+// Rooms record their own messages, in the order they broadcast them, and a
+// DM is recorded by its sender once it's delivered. Either way it's the same
+// Relayed everyone saw, so the time and msgid match on playback.
+//
+// A room has one log, which anyone in the room can read. A conversation has
+// two, one for each end, and each belongs to its end's Owner: whoever has a
+// nick later can't read what was said to the last person who had it.
+
+use std::{collections::{HashSet, VecDeque}, fs, io::{self, BufRead, BufReader, BufWriter, Write}, ops::Range, path::Path, sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use tokio::time::Instant;
+
+use crate::{casemap::{CaseMapping, Folded}, config::HistoryConfig, parse, protocol::{IRCString, Relayed}, shard::ShardedMap, sock::MessageIn, user::UserID};
+
+// Whose end of a conversation a log is. An account follows its owner from
+// one connection to the next (and across restarts), but without one, the
+// log is only ever for the connection that was there.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Owner {
+    Account(Folded),
+    Connection(UserID),
+}
+
+// Which log, by name.
+pub enum LogName<'a> {
+    Room(&'a IRCString),
+    // whose end, and who it's with
+    Conversation(&'a Owner, &'a IRCString),
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum Target {
+    Room(Folded),
+    Conversation(Owner, Folded),
+}
+
+// Somewhere in a log, to look from.
+pub enum Point {
+    Msgid(IRCString),
+    Time(SystemTime),
+}
+
+pub enum Query {
+    // the newest messages (after the point, if there is one)
+    Latest(Option<Point>),
+    Before(Point),
+    After(Point),
+    Around(Point),
+    Between(Point, Point),
+}
+
+struct Log {
+    target: Target,
+    // the room, or whoever it's with, the way they were last typed
+    name: IRCString,
+    messages: VecDeque<Arc<Relayed>>,
+}
+
+pub struct History {
+    casemapping: CaseMapping,
+    max_messages: usize,
+    max_age: Duration,
+    logs: ShardedMap<Target, Arc<Mutex<Log>>>,
+    // everyone each Owner has a log of a conversation with, so they can be
+    // forgotten together
+    partners: ShardedMap<Owner, HashSet<Folded>>,
+}
+
+impl Point {
+    // `msgid=...` or `timestamp=...`
+    pub fn parse(s: &[u8]) -> Option<Point> {
+        if let Some(msgid) = s.strip_prefix(b"msgid=") {
+            return Some(Point::Msgid(IRCString::new(msgid.to_vec())))
+        }
+        s.strip_prefix(b"timestamp=").and_then(crate::tags::parse_server_time).map(Point::Time)
+    }
+}
+
+impl History {
+    pub fn new(casemapping: CaseMapping, config: &HistoryConfig) -> Self {
+        History {
+            casemapping,
+            max_messages: config.max_messages,
+            max_age: config.max_age,
+            logs: ShardedMap::new(),
+            partners: ShardedMap::new(),
+        }
+    }
+
+    // `user`'s Owner, if they're logged in as `account` (or not).
+    pub fn owner(&self, user: UserID, account: Option<&IRCString>) -> Owner {
+        match account {
+            Some(a) => Owner::Account(self.casemapping.fold(a)),
+            None => Owner::Connection(user),
+        }
+    }
+
+    fn target(&self, name: &LogName) -> Target {
+        match name {
+            LogName::Room(room) => Target::Room(self.casemapping.fold(room)),
+            LogName::Conversation(owner, with) => Target::Conversation((*owner).clone(), self.casemapping.fold(with)),
+        }
+    }
+
+    // (typing notifications and such aren't worth keeping)
+    pub fn record(&self, name: LogName, relayed: Arc<Relayed>) {
+        if relayed.command.cmd.bytes == b"TAGMSG" { return }
+        let display = match name {
+            LogName::Room(n) | LogName::Conversation(_, n) => n.clone(),
+        };
+        self.record_target(self.target(&name), display, relayed)
+    }
+
+    fn record_target(&self, target: Target, name: IRCString, relayed: Arc<Relayed>) {
+        let log = match self.logs.get(&target) {
+            Some(log) => log,
+            None => {
+                if let Target::Conversation(owner, with) = &target {
+                    let with = with.clone();
+                    self.partners.update(owner.clone(), |p| {
+                        let mut p = p.unwrap_or_default();
+                        p.insert(with);
+                        Some(p)
+                    });
+                }
+                let fresh = Arc::new(Mutex::new(Log { target: target.clone(), name: name.clone(), messages: VecDeque::new() }));
+                match self.logs.insert_unless(target, fresh.clone(), |_| true) {
+                    Ok(()) => fresh,
+                    Err(log) => log,
+                }
+            }
+        };
+
+        let mut log = log.lock().unwrap();
+        log.name = name;
+        log.messages.push_back(relayed);
+        self.expire(&mut log);
+    }
+
+    fn expire(&self, log: &mut Log) {
+        while log.messages.len() > self.max_messages { log.messages.pop_front(); }
+        let cutoff = SystemTime::now().checked_sub(self.max_age).unwrap_or(UNIX_EPOCH);
+        while log.messages.front().is_some_and(|m| m.time < cutoff) { log.messages.pop_front(); }
+    }
+
+    // Drop every conversation `owner` has a log of. (For connections that are
+    // gone, since nobody can ever read those again.)
+    pub fn forget(&self, owner: &Owner) {
+        for with in self.partners.remove(owner).unwrap_or_default() {
+            self.logs.remove(&Target::Conversation(owner.clone(), with));
+        }
+    }
+
+    // Up to `limit` messages, oldest first. (Nothing, if a msgid isn't there anymore.)
+    pub fn query(&self, name: LogName, query: &Query, limit: usize) -> Vec<Arc<Relayed>> {
+        let log = match self.logs.get(&self.target(&name)) {
+            Some(log) => log,
+            None => return vec![]
+        };
+        let mut log = log.lock().unwrap();
+        self.expire(&mut log);
+        let messages = log.messages.make_contiguous();
+        let range = select(messages, query, limit).unwrap_or(0..0);
+        messages[range].to_vec()
+    }
+
+    // The rooms (from `rooms`) and conversations (of `me`'s) that had anything
+    // said between `from` and `to`, by name, along with the time of the latest
+    // thing said. Oldest first.
+    pub fn targets(&self, me: &Owner, rooms: &[IRCString], from: SystemTime, to: SystemTime, limit: usize) -> Vec<(IRCString, SystemTime)> {
+        let (from, to) = (from.min(to), from.max(to));
+        let rooms: Vec<Folded> = rooms.iter().map(|r| self.casemapping.fold(r)).collect();
+
+        let mut found = vec![];
+        for log in self.logs.values() {
+            let log = log.lock().unwrap();
+            match &log.target {
+                Target::Room(room) if rooms.contains(room) => {}
+                Target::Conversation(owner, _) if owner == me => {}
+                _ => continue
+            }
+            let latest = log.messages.iter().rev().map(|m| m.time).find(|t| *t >= from && *t <= to);
+            if let Some(latest) = latest { found.push((log.name.clone(), latest)); }
+        }
+        found.sort_by_key(|(_, t)| *t);
+        found.truncate(limit);
+        found
+    }
+
+    // One message per line: `<unix millis> <msgid> <log> <the message>`, where
+    // the log is `room:<room>` or `dm:<account>,<who with>`. Conversations that
+    // only belong to a connection aren't saved: after a restart, they'd be
+    // nobody's.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let partial = path.with_extension("partial");
+        let mut out = BufWriter::new(fs::File::create(&partial)?);
+        for log in self.logs.values() {
+            let mut log = log.lock().unwrap();
+            self.expire(&mut log);
+            let mut name = match &log.target {
+                Target::Room(_) => b"room:".to_vec(),
+                Target::Conversation(Owner::Account(account), _) => {
+                    let mut name = b"dm:".to_vec();
+                    name.extend(account.as_bytes());
+                    name.push(b',');
+                    name
+                }
+                Target::Conversation(Owner::Connection(_), _) => continue,
+            };
+            name.extend(&log.name.bytes);
+            for m in log.messages.iter() {
+                let millis = m.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+                write!(out, "{} ", millis)?;
+                out.write_all(&m.msgid.bytes)?;
+                out.write_all(b" ")?;
+                out.write_all(&name)?;
+                out.write_all(b" ")?;
+                out.write_all(&parse::dump_unsplit(&m.command))?;
+                out.write_all(b"\n")?;
+            }
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(partial, path)
+    }
+
+    // Lines we can't make sense of are skipped. Returns how many there were.
+    pub fn load(&self, path: &Path) -> io::Result<usize> {
+        let file = match fs::File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e)
+        };
+        let mut skipped = 0;
+        for line in BufReader::new(file).split(b'\n') {
+            match self.restore(&line?) {
+                Some((target, name, relayed)) => self.record_target(target, name, relayed),
+                None => skipped += 1,
+            }
+        }
+        Ok(skipped)
+    }
+
+    fn restore(&self, line: &[u8]) -> Option<(Target, IRCString, Arc<Relayed>)> {
+        let mut fields = line.splitn(4, |b| *b == b' ');
+        let (millis, msgid, name, message) = (fields.next()?, fields.next()?, fields.next()?, fields.next()?);
+        let millis: u64 = std::str::from_utf8(millis).ok()?.parse().ok()?;
+        let (target, name) = if let Some(room) = name.strip_prefix(b"room:") {
+            let room = IRCString::new(room.to_vec());
+            (Target::Room(self.casemapping.fold(&room)), room)
+        } else {
+            let dm = name.strip_prefix(b"dm:")?;
+            let comma = dm.iter().position(|b| *b == b',')?;
+            let (account, with) = (IRCString::new(dm[..comma].to_vec()), IRCString::new(dm[comma + 1..].to_vec()));
+            if account.bytes.is_empty() || with.bytes.is_empty() { return None }
+            (Target::Conversation(Owner::Account(self.casemapping.fold(&account)), self.casemapping.fold(&with)), with)
+        };
+        let command = parse::parse(&MessageIn { time: Instant::now(), data: IRCString::new(message.to_vec()) })?;
+        let time = UNIX_EPOCH.checked_add(Duration::from_millis(millis))?;
+        Some((target, name, Relayed::restore(command, time, IRCString::new(msgid.to_vec()))))
+    }
+}
+
+// Where `point` falls: everything before .0 is before it, and everything from .1 on is after it.
+fn locate(messages: &[Arc<Relayed>], point: &Point) -> Option<(usize, usize)> {
+    match point {
+        Point::Msgid(msgid) => messages.iter().position(|m| m.msgid == *msgid).map(|i| (i, i + 1)),
+        Point::Time(t) => Some((messages.partition_point(|m| m.time < *t), messages.partition_point(|m| m.time <= *t))),
+    }
+}
+
+fn select(messages: &[Arc<Relayed>], query: &Query, limit: usize) -> Option<Range<usize>> {
+    let first = |r: Range<usize>| r.start..r.end.min(r.start.saturating_add(limit));
+    let last = |r: Range<usize>| r.end.saturating_sub(limit).max(r.start)..r.end;
+    let len = messages.len();
+
+    Some(match query {
+        Query::Latest(None) => last(0..len),
+        Query::Latest(Some(p)) => last(locate(messages, p)?.1..len),
+        Query::Before(p) => last(0..locate(messages, p)?.0),
+        Query::After(p) => first(locate(messages, p)?.1..len),
+        Query::Around(p) => {
+            let start = locate(messages, p)?.0.saturating_sub(limit / 2);
+            first(start..len)
+        }
+        // the end that's nearest to `a`
+        Query::Between(a, b) => {
+            let ((a0, a1), (b0, b1)) = (locate(messages, a)?, locate(messages, b)?);
+            if a1 <= b0 { first(a1..b0) } else if b1 <= a0 { last(b1..a0) } else { 0..0 }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ops::Range, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+    use slotmap::SlotMap;
+
+    use crate::{casemap::CaseMapping, config::HistoryConfig, protocol::{Command, IRCString, Relayed}, user::UserID};
+
+    use super::{select, History, LogName, Owner, Point, Query};
+
+    // "m0" to "m{n-1}", a second apart
+    fn log(n: u64) -> Vec<Arc<Relayed>> {
+        (0..n).map(|i| message(&format!("m{}", i), at(i))).collect()
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        // (recent enough that max_age doesn't expire it)
+        SystemTime::now() - Duration::from_secs(3600) + Duration::from_secs(secs)
+    }
+
+    fn message(msgid: &str, time: SystemTime) -> Arc<Relayed> {
+        let command = Command {
+            tags: vec![],
+            pfx: Some(IRCString::from("alice!a@localhost")),
+            cmd: IRCString::from("PRIVMSG"),
+            args: vec![IRCString::from("#r"), IRCString::from(msgid)],
+        };
+        Relayed::restore(command, time, IRCString::from(msgid))
+    }
+
+    fn id(s: &str) -> Point {
+        Point::Msgid(IRCString::from(s))
+    }
+
+    fn msgids(messages: &[Arc<Relayed>]) -> Vec<String> {
+        messages.iter().map(|m| String::from_utf8(m.msgid.bytes.clone()).unwrap()).collect()
+    }
+
+    fn sel(n: u64, query: Query, limit: usize) -> Option<Range<usize>> {
+        select(&log(n), &query, limit)
+    }
+
+    #[test]
+    fn latest() {
+        assert_eq!(sel(10, Query::Latest(None), 3), Some(7..10));
+        assert_eq!(sel(10, Query::Latest(None), 50), Some(0..10));
+        assert_eq!(sel(10, Query::Latest(Some(id("m5"))), 10), Some(6..10));
+        assert_eq!(sel(10, Query::Latest(Some(id("m5"))), 2), Some(8..10));
+        assert_eq!(sel(10, Query::Latest(Some(id("m9"))), 2), Some(10..10));
+    }
+
+    #[test]
+    fn before_and_after() {
+        assert_eq!(sel(10, Query::Before(id("m5")), 2), Some(3..5));
+        assert_eq!(sel(10, Query::Before(id("m5")), 50), Some(0..5));
+        assert_eq!(sel(10, Query::Before(id("m0")), 2), Some(0..0));
+        assert_eq!(sel(10, Query::After(id("m5")), 2), Some(6..8));
+        assert_eq!(sel(10, Query::After(id("m5")), 50), Some(6..10));
+        assert_eq!(sel(10, Query::After(id("m9")), 2), Some(10..10));
+    }
+
+    #[test]
+    fn timestamps_exclude_messages_at_that_time() {
+        let messages = log(10);
+        assert_eq!(select(&messages, &Query::Before(Point::Time(messages[5].time)), 2), Some(3..5));
+        assert_eq!(select(&messages, &Query::After(Point::Time(messages[5].time)), 2), Some(6..8));
+        // and between two messages, it's just a place in the log
+        let between = messages[5].time + Duration::from_millis(500);
+        assert_eq!(select(&messages, &Query::Before(Point::Time(between)), 2), Some(4..6));
+        assert_eq!(select(&messages, &Query::After(Point::Time(between)), 2), Some(6..8));
+    }
+
+    #[test]
+    fn around() {
+        assert_eq!(sel(10, Query::Around(id("m5")), 4), Some(3..7));
+        assert_eq!(sel(10, Query::Around(id("m0")), 4), Some(0..4));
+        assert_eq!(sel(10, Query::Around(id("m9")), 4), Some(7..10));
+        assert_eq!(sel(10, Query::Around(id("m5")), 50), Some(0..10));
+    }
+
+    #[test]
+    fn between() {
+        assert_eq!(sel(10, Query::Between(id("m2"), id("m7")), 10), Some(3..7));
+        assert_eq!(sel(10, Query::Between(id("m2"), id("m7")), 2), Some(3..5));
+        // backwards, it's the end nearest the first point that counts
+        assert_eq!(sel(10, Query::Between(id("m7"), id("m2")), 10), Some(3..7));
+        assert_eq!(sel(10, Query::Between(id("m7"), id("m2")), 2), Some(5..7));
+        assert_eq!(sel(10, Query::Between(id("m4"), id("m4")), 10), Some(0..0));
+        assert_eq!(sel(10, Query::Between(id("m4"), id("m5")), 10), Some(5..5));
+    }
+
+    #[test]
+    fn empty_log() {
+        let t = || Point::Time(at(0));
+        for query in [Query::Latest(None), Query::Latest(Some(t())), Query::Before(t()), Query::After(t()), Query::Around(t()), Query::Between(t(), t())] {
+            assert_eq!(select(&[], &query, 10), Some(0..0));
+        }
+    }
+
+    #[test]
+    fn missing_msgid() {
+        let gone = || id("nope");
+        for query in [Query::Latest(Some(gone())), Query::Before(gone()), Query::After(gone()), Query::Around(gone()), Query::Between(gone(), id("m2")), Query::Between(id("m2"), gone())] {
+            assert_eq!(sel(10, query, 10), None);
+        }
+    }
+
+    fn history() -> History {
+        History::new(CaseMapping::Rfc1459, &HistoryConfig::default())
+    }
+
+    #[test]
+    fn conversations_belong_to_their_owner() {
+        let mut ids = SlotMap::<UserID, ()>::with_key();
+        let (first, second) = (ids.insert(()), ids.insert(()));
+        let history = history();
+        let bob = IRCString::from("Bob");
+        history.record(LogName::Conversation(&Owner::Connection(first), &bob), message("m0", at(0)));
+
+        let latest = |owner: &Owner| msgids(&history.query(LogName::Conversation(owner, &IRCString::from("bob")), &Query::Latest(None), 10));
+        assert_eq!(latest(&Owner::Connection(first)), ["m0"]);
+        assert!(latest(&Owner::Connection(second)).is_empty());
+
+        history.forget(&Owner::Connection(first));
+        assert!(latest(&Owner::Connection(first)).is_empty());
+    }
+
+    #[test]
+    fn restore() {
+        let history = history();
+        let (target, name, relayed) = history.restore(b"1319042451620 abc room:#Room :alice!a@localhost PRIVMSG #Room :hi there").unwrap();
+        assert_eq!(target, super::Target::Room(CaseMapping::Rfc1459.fold(&IRCString::from("#room"))));
+        assert_eq!(&name.bytes[..], b"#Room");
+        assert_eq!(&relayed.msgid.bytes[..], b"abc");
+        assert_eq!(relayed.time, UNIX_EPOCH + Duration::from_millis(1_319_042_451_620));
+        assert_eq!(&relayed.command.args[1].bytes[..], b"hi there");
+
+        let (target, name, _) = history.restore(b"0 abc dm:Alice,Bob :alice!a@localhost PRIVMSG Bob :hi").unwrap();
+        assert_eq!(target, super::Target::Conversation(Owner::Account(CaseMapping::Rfc1459.fold(&IRCString::from("alice"))), CaseMapping::Rfc1459.fold(&IRCString::from("bob"))));
+        assert_eq!(&name.bytes[..], b"Bob");
+
+        for line in [
+            &b"1319042451620 abc #room,bob PRIVMSG #room :the old format"[..],
+            b"soon abc room:#room PRIVMSG #room :hi",
+            b"0 abc dm:,bob PRIVMSG bob :hi",
+            b"0 abc dm:alice PRIVMSG bob :hi",
+            b"0 abc room:#room",
+            b"",
+        ] {
+            assert!(history.restore(line).is_none(), "{}", String::from_utf8_lossy(line));
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut ids = SlotMap::<UserID, ()>::with_key();
+        let history = history();
+        let (room, bob) = (IRCString::from("#Room"), IRCString::from("Bob"));
+        let account = history.owner(ids.insert(()), Some(&IRCString::from("Alice")));
+        let connection = history.owner(ids.insert(()), None);
+        let originals = log(3);
+        for (i, m) in originals.iter().enumerate() {
+            history.record(LogName::Room(&room), m.clone());
+            if i < 2 { history.record(LogName::Conversation(&account, &bob), m.clone()); }
+            history.record(LogName::Conversation(&connection, &bob), m.clone());
+        }
+
+        let path = std::env::temp_dir().join(format!("batircd-history-test-{}", std::process::id()));
+        history.save(&path).unwrap();
+        let loaded = self::history();
+        let skipped = loaded.load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(skipped.unwrap(), 0);
+
+        let latest = |name| loaded.query(name, &Query::Latest(None), 10);
+        let lowercase = IRCString::from("#room");
+        let messages = latest(LogName::Room(&lowercase));
+        assert_eq!(msgids(&messages), ["m0", "m1", "m2"]);
+        for (m, original) in messages.iter().zip(&originals) {
+            assert_eq!(m.time.duration_since(UNIX_EPOCH).unwrap().as_millis(), original.time.duration_since(UNIX_EPOCH).unwrap().as_millis());
+            assert_eq!(m.command.args[1].bytes, original.command.args[1].bytes);
+        }
+        let alice = loaded.owner(ids.insert(()), Some(&IRCString::from("ALICE")));
+        assert_eq!(msgids(&latest(LogName::Conversation(&alice, &bob))), ["m0", "m1"]);
+        // (nobody could ever read this one again)
+        assert!(latest(LogName::Conversation(&connection, &bob)).is_empty());
+
+        let targets = loaded.targets(&alice, &[], UNIX_EPOCH, SystemTime::now(), 10);
+        assert_eq!(targets.iter().map(|(name, _)| &name.bytes[..]).collect::<Vec<_>>(), [b"Bob"]);
+    }
+}
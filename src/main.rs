@@ -1,20 +1,39 @@
 mod cancel;
+mod caps;
+mod casemap;
+mod config;
 mod directory;
+mod encoding;
+mod flood;
+mod history;
+mod metrics;
+mod names;
 mod parse;
 mod protocol;
+mod response;
 mod room;
+mod sasl;
+mod shard;
 mod subscriptions;
 mod sock;
+mod supervise;
+mod tags;
 mod user;
 mod world;
 
+use config::Config;
 use world::World;
 
 #[tokio::main(flavor="multi_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // let listener = TcpListener::bind("127.0.0.1:6667").await?;
 
-    let mut world = World::new();
+    // batircd [config file]
+    let config = match std::env::args_os().nth(1) {
+        Some(path) => Config::load(path.as_ref())?,
+        None => Config::default(),
+    };
+    let world = World::new(config);
     world.main_loop().await?;
     Ok(())
 }
@@ -0,0 +1,7 @@
+use std::sync::atomic::AtomicU64;
+
+// Server-wide counters. Bump with Ordering::Relaxed: nobody synchronizes on these.
+#[derive(Default)]
+pub struct Metrics {
+    pub sendq_exceeded: AtomicU64,
+}
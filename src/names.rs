@@ -0,0 +1,45 @@
+use crate::{config::{NameRules, Config}, casemap::CaseMapping, protocol::IRCString};
+
+// What's allowed in nicks, usernames and channel names.
+// Everything here is advertised in ISUPPORT, so clients can check before asking.
+
+// []\`_^{|} are "special" in RFC 2812 and have always been allowed in nicks
+fn is_nick_special(b: u8) -> bool {
+    matches!(b, b'[' | b']' | b'\\' | b'`' | b'_' | b'^' | b'{' | b'|' | b'}')
+}
+
+pub fn valid_nick(config: &Config, nick: &IRCString) -> bool {
+    let rules = &config.names;
+    let bytes = &nick.bytes;
+    if bytes.is_empty() || bytes.len() > rules.nicklen { return false }
+
+    // non-ASCII only makes sense if we'd know how to fold it
+    let unicode_ok = matches!(config.casemapping, CaseMapping::Rfc7613) && std::str::from_utf8(bytes).is_ok();
+
+    let first_ok = bytes[0].is_ascii_alphabetic() || is_nick_special(bytes[0]) || (unicode_ok && bytes[0] >= 0x80);
+    let rest_ok = bytes[1..].iter().all(|&b| {
+        b.is_ascii_alphanumeric() || is_nick_special(b) || b == b'-' || (unicode_ok && b >= 0x80)
+    });
+    if !(first_ok && rest_ok) { return false }
+
+    let folded = config.casemapping.fold(nick);
+    !rules.reserved_nicks.iter().any(|r| config.casemapping.fold(&IRCString::from(r.as_str())) == folded)
+}
+
+// Usernames that are too long get cut down to size rather than rejected, like everywhere else.
+pub fn clean_username(rules: &NameRules, user: &IRCString) -> Option<IRCString> {
+    let bytes = &user.bytes;
+    if bytes.is_empty() { return None }
+    if !bytes.iter().all(|&b| b > b' ' && b != b'@' && b != 0x7f) { return None }
+
+    Some(IRCString::new(bytes[..bytes.len().min(rules.userlen)].to_vec()))
+}
+
+pub fn valid_channel(rules: &NameRules, name: &IRCString) -> bool {
+    let bytes = &name.bytes;
+    if bytes.len() < 2 || bytes.len() > rules.channellen { return false }
+    if !rules.chantypes.as_bytes().contains(&bytes[0]) { return false }
+
+    // space and comma would split it, ^G is a classic prank
+    bytes.iter().all(|&b| !matches!(b, 0 | b'\r' | b'\n' | b' ' | b',' | 0x07 | b':'))
+}
@@ -1,11 +1,20 @@
-use std::{borrow::Borrow, time::Duration};
+use std::time::Duration;
 
+use bytes::Bytes;
 use tokio::time::Instant;
 
-use crate::{sock::{MessageIn, MessageOut}, protocol::{Command, IRCString}};
+use crate::{sock::{MessageIn, MessageOut}, protocol::{Command, IRCString}, tags::{Tag, parse_tags, write_tags}};
 
 pub fn parse(msg: &MessageIn) -> Option<Command> {
     let mut data: &[u8] = &msg.data.bytes;
+    let tags = if data.starts_with(b"@") {
+        let tags_end = data.iter().position(|b| *b == b' ').unwrap_or(data.len());
+        let t = &data[1..tags_end];
+        data = &data[(tags_end+1).min(data.len())..];
+        parse_tags(t)
+    } else {
+        vec![]
+    };
     let pfx = if data.starts_with(b":") {
         let pfx_start = 1;
         let mut pfx_end = 1;
@@ -20,12 +29,13 @@ pub fn parse(msg: &MessageIn) -> Option<Command> {
         None
     };
 
-    let mut args = split_args(&data);
-    if args.len() == 0 { return None }
+    let mut args = split_args(data);
+    if args.is_empty() { return None }
     let mut cmd = args.remove(0);
     cmd.upper_inplace();
 
     Some(Command { 
+        tags,
         pfx,
         cmd,
         args,
@@ -43,37 +53,327 @@ fn split_args(mut src: &[u8]) -> Vec<IRCString> {
         if arg_start >= src.len() { break; }
 
         if src[arg_start] == b':' {
-            out.push(IRCString::new((&src[arg_start + 1..]).to_vec()));
+            out.push(IRCString::new(src[arg_start + 1..].to_vec()));
             break;
         }
 
         while arg_end < src.len() && src[arg_end] != b' ' {
             arg_end += 1;
         }
-        out.push(IRCString::new((&src[arg_start..arg_end]).to_vec()));
+        out.push(IRCString::new(src[arg_start..arg_end].to_vec()));
 
         src = &src[(arg_end+1).min(src.len())..]
     };
 
-    return out
+    out
+}
+
+// Lines are 512 bytes, CRLF included.
+const MAX_LINE: usize = 510;
+
+// IRCv3 caps the client-visible params of one line at 15.
+const MAX_PARAMS: usize = 15;
+
+fn deadline(deadline_seconds: f32) -> Instant {
+    Instant::now().checked_add(Duration::from_secs_f32(deadline_seconds)).unwrap()
 }
 
+// Serializes `command`. If it won't fit on one line, the last argument gets
+// split up and the rest of the command is repeated on each line.
 pub fn dump(command: Command, deadline_seconds: f32) -> MessageOut {
-    // TODO: Break up long messages
     let mut out = vec![];
-    if let Some(pfx) = command.pfx {
+    let mut args = command.args;
+    let last = args.pop();
+
+    let mut head = vec![];
+    write_head(&mut head, command.pfx.as_ref(), &command.cmd, &args);
+
+    match last {
+        None => { write_line(&mut out, &line_tags(&command.tags, 0), &head, None) }
+        Some(last) => {
+            // the budget for the last argument, after its space and colon
+            // (tags have a budget of their own)
+            let budget = MAX_LINE.saturating_sub(head.len() + 2);
+            if last.bytes.len() <= budget || budget == 0 {
+                write_line(&mut out, &line_tags(&command.tags, 0), &head, Some(&last.bytes));
+            } else {
+                for (i, chunk) in split_text(&last.bytes, budget).into_iter().enumerate() {
+                    write_line(&mut out, &line_tags(&command.tags, i), &head, Some(chunk));
+                }
+            }
+        }
+    }
+
+    MessageOut { deadline: deadline(deadline_seconds), data: Bytes::from(out) }
+}
+
+// For saving `command` somewhere other than a socket: one line (without
+// the CRLF), however long it is.
+pub fn dump_unsplit(command: &Command) -> Vec<u8> {
+    let mut out = vec![];
+    let (last, args) = match command.args.split_last() {
+        Some((last, args)) => (Some(&last.bytes[..]), args),
+        None => (None, &command.args[..]),
+    };
+    let mut head = vec![];
+    write_head(&mut head, command.pfx.as_ref(), &command.cmd, args);
+    write_line(&mut out, &line_tags(&command.tags, 0), &head, last);
+    out.truncate(out.len() - 2);
+    out
+}
+
+// How dump_packed lays out a list of items.
+pub enum Packing {
+    // as separate params, followed by a closing trailing param (ISUPPORT)
+    Params { trailer: IRCString },
+    // joined by spaces in the trailing param (NAMES)
+    Trailing,
+    // joined by commas in the trailing param (MONITOR)
+    Commas,
+}
+
+// For replies that are really a list (NAMES, ISUPPORT...): repeat `command`
+// with as many of `items` on each line as will fit.
+pub fn dump_packed(command: Command, items: Vec<IRCString>, packing: Packing, deadline_seconds: f32) -> MessageOut {
+    let mut out = vec![];
+    let mut head = vec![];
+    write_head(&mut head, command.pfx.as_ref(), &command.cmd, &command.args);
+    let tags = line_tags(&command.tags, 0);
+
+    match packing {
+        Packing::Params { trailer } => {
+            let max_items = MAX_PARAMS.saturating_sub(command.args.len() + 1).max(1);
+            let budget = MAX_LINE.saturating_sub(head.len() + trailer.bytes.len() + 2);
+
+            let mut line = head.clone();
+            let mut n = 0;
+            for item in items {
+                if n > 0 && (n == max_items || line.len() - head.len() + 1 + item.bytes.len() > budget) {
+                    write_line(&mut out, &tags, &line, Some(&trailer.bytes));
+                    line = head.clone();
+                    n = 0;
+                }
+                line.push(b' ');
+                line.extend(&item.bytes);
+                n += 1;
+            }
+            if n > 0 { write_line(&mut out, &tags, &line, Some(&trailer.bytes)); }
+        }
+        Packing::Trailing | Packing::Commas => {
+            let separator = if let Packing::Commas = packing { b',' } else { b' ' };
+            let budget = MAX_LINE.saturating_sub(head.len() + 2);
+
+            let mut text: Vec<u8> = vec![];
+            for item in items {
+                if !text.is_empty() && text.len() + 1 + item.bytes.len() > budget {
+                    write_line(&mut out, &tags, &head, Some(&text));
+                    text.clear();
+                }
+                if !text.is_empty() { text.push(separator); }
+                text.extend(&item.bytes);
+            }
+            if !text.is_empty() { write_line(&mut out, &tags, &head, Some(&text)); }
+        }
+    }
+
+    MessageOut { deadline: deadline(deadline_seconds), data: Bytes::from(out) }
+}
+
+fn write_head(out: &mut Vec<u8>, pfx: Option<&IRCString>, cmd: &IRCString, args: &[IRCString]) {
+    if let Some(pfx) = pfx {
         out.push(b':');
-        out.extend(pfx.bytes);
+        out.extend(&pfx.bytes);
+        out.push(b' ');
+    }
+    out.extend(&cmd.bytes);
+    for a in args {
         out.push(b' ');
+        out.extend(&a.bytes);
     }
-    out.extend(command.cmd.bytes);
-    for a in command.args {
+}
+
+// Tags for the `n`th line of a command that had to be split. Each of those
+// lines is a message of its own, so they can't all have the same msgid.
+fn line_tags(tags: &[Tag], n: usize) -> Vec<u8> {
+    let mut out = vec![];
+    if n == 0 {
+        write_tags(&mut out, tags);
+    } else {
+        let tags: Vec<Tag> = tags.iter().map(|t| {
+            if t.key.bytes != b"msgid" { return t.clone() }
+            let mut value = t.value.bytes.clone();
+            value.extend(format!("-{}", n).bytes());
+            Tag { key: t.key.clone(), value: IRCString::new(value) }
+        }).collect();
+        write_tags(&mut out, &tags);
+    }
+    out
+}
+
+fn write_line(out: &mut Vec<u8>, tags: &[u8], head: &[u8], last: Option<&[u8]>) {
+    out.extend(tags);
+    out.extend(head);
+    if let Some(last) = last {
         out.push(b' ');
-        out.extend(a.bytes);
+        if needs_colon(last) { out.push(b':'); }
+        out.extend(last);
     }
     out.extend(b"\r\n");
-    MessageOut { 
-        deadline: Instant::now().checked_add(Duration::from_secs_f32(deadline_seconds)).unwrap(), 
-        data: IRCString::new(out)
+}
+
+// the last argument can only contain spaces (or be empty) if it's marked as trailing
+fn needs_colon(arg: &[u8]) -> bool {
+    arg.is_empty() || arg.starts_with(b":") || arg.contains(&b' ')
+}
+
+// Cut `text` into pieces of at most `budget` bytes, never in the middle of a
+// UTF-8 character, and at a space if there's one reasonably close to the end.
+fn split_text(mut text: &[u8], budget: usize) -> Vec<&[u8]> {
+    let mut out = vec![];
+    while text.len() > budget {
+        let mut cut = budget;
+        while cut > 0 && is_utf8_continuation(text[cut]) { cut -= 1; }
+        if cut == 0 { cut = budget; }  // not UTF-8 after all, so any cut will do
+
+        match text[..cut].iter().rposition(|b| *b == b' ') {
+            Some(space) if space > cut / 2 => {
+                out.push(&text[..space]);
+                text = &text[space + 1..];
+            }
+            _ => {
+                out.push(&text[..cut]);
+                text = &text[cut..];
+            }
+        }
     }
-}
\ No newline at end of file
+    out.push(text);
+    out
+}
+
+fn is_utf8_continuation(b: u8) -> bool {
+    b & 0xC0 == 0x80
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{protocol::{Command, IRCString}, sock::MessageOut, tags::Tag};
+
+    use super::{dump, dump_packed, Packing, MAX_LINE, MAX_PARAMS};
+
+    fn command(tags: &[(&str, &str)], pfx: Option<&str>, cmd: &str, args: &[&str]) -> Command {
+        Command {
+            tags: tags.iter().map(|(k, v)| Tag { key: IRCString::from(*k), value: IRCString::from(*v) }).collect(),
+            pfx: pfx.map(IRCString::from),
+            cmd: IRCString::from(cmd),
+            args: args.iter().map(|a| IRCString::from(*a)).collect(),
+        }
+    }
+
+    // each line, without its CRLF, split into its tags and the rest
+    fn lines(out: &MessageOut) -> Vec<(&[u8], &[u8])> {
+        assert!(out.data.ends_with(b"\r\n"));
+        out.data[..out.data.len() - 1].split(|b| *b == b'\n').map(|line| {
+            let line = line.strip_suffix(b"\r").expect("bare LF");
+            assert!(!line.contains(&b'\r'));
+            match line.strip_prefix(b"@") {
+                Some(rest) => {
+                    let space = rest.iter().position(|b| *b == b' ').unwrap();
+                    (&rest[..space], &rest[space + 1..])
+                }
+                None => (&b""[..], line)
+            }
+        }).collect()
+    }
+
+    // what follows `head` on a line: the last argument
+    fn after<'a>(line: &'a [u8], head: &[u8]) -> &'a [u8] {
+        let rest = line.strip_prefix(head).unwrap_or_else(|| panic!("{}", String::from_utf8_lossy(line)));
+        rest.strip_prefix(b":").unwrap_or(rest)
+    }
+
+    #[test]
+    fn dump_splits_at_the_limit_with_tags_and_a_prefix() {
+        let text = "lorem ipsum ".repeat(120);
+        let text = text.trim_end();
+        let pfx = "somebody!someone@some.where.example.org";
+        let out = dump(command(&[("time", "2011-10-19T16:40:51.620Z"), ("msgid", "abc")], Some(pfx), "PRIVMSG", &["#room", text]), 1.0);
+        let lines = lines(&out);
+        assert!(lines.len() > 2);
+
+        let head = format!(":{} PRIVMSG #room ", pfx);
+        let mut pieces = vec![];
+        for (i, (tags, line)) in lines.iter().enumerate() {
+            // (the tags don't count against the 512)
+            assert!(line.len() <= MAX_LINE, "{} bytes", line.len());
+            let msgid = if i == 0 { "abc".to_string() } else { format!("abc-{}", i) };
+            assert_eq!(*tags, format!("time=2011-10-19T16:40:51.620Z;msgid={}", msgid).as_bytes());
+            pieces.push(after(line, head.as_bytes()));
+        }
+        // all but the last line come out as full as a cut at a space allows
+        for (_, line) in &lines[..lines.len() - 1] {
+            assert!(line.len() > MAX_LINE - "lorem ipsum ".len(), "{} bytes", line.len());
+        }
+        assert_eq!(pieces.join(&b' '), text.as_bytes());
+    }
+
+    #[test]
+    fn dump_leaves_short_lines_alone() {
+        let out = dump(command(&[("msgid", "abc")], Some("n!u@h"), "PRIVMSG", &["#room", "hi there"]), 1.0);
+        assert_eq!(&out.data[..], b"@msgid=abc :n!u@h PRIVMSG #room :hi there\r\n");
+    }
+
+    #[test]
+    fn dump_never_cuts_a_character_in_half() {
+        for c in ["é", "€", "😀"] {
+            let text = c.repeat(400);
+            // (different prefix lengths put the budget at different offsets into a character)
+            for pfx in ["n", "nn", "nnn", "nnnn"] {
+                let out = dump(command(&[], Some(pfx), "NOTICE", &["someone", &text]), 1.0);
+                let head = format!(":{} NOTICE someone ", pfx);
+                let mut joined: Vec<u8> = vec![];
+                for (_, line) in lines(&out) {
+                    assert!(line.len() <= MAX_LINE);
+                    let piece = after(line, head.as_bytes());
+                    assert!(std::str::from_utf8(piece).is_ok(), "{} {}", c, pfx);
+                    joined.extend(piece);
+                }
+                assert_eq!(joined, text.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn dump_packed_fills_lines_in_order() {
+        let items: Vec<String> = (0..300).map(|i| format!("nick{}", i)).collect();
+        let as_items = || items.iter().map(|i| IRCString::from(i.as_str())).collect::<Vec<_>>();
+
+        for (packing, separator) in [(Packing::Trailing, b' '), (Packing::Commas, b',')] {
+            let out = dump_packed(command(&[], Some("irc.example.org"), "353", &["me", "=", "#room"]), as_items(), packing, 1.0);
+            let lines = lines(&out);
+            assert!(lines.len() > 1);
+            let mut got = vec![];
+            for (i, (_, line)) in lines.iter().enumerate() {
+                assert!(line.len() <= MAX_LINE);
+                let packed = after(line, b":irc.example.org 353 me = #room ");
+                // a line only ends early if the next item wouldn't have fit
+                if i + 1 < lines.len() { assert!(line.len() + 1 + "nick300".len() > MAX_LINE) }
+                got.extend(packed.split(|b| *b == separator).map(|s| String::from_utf8(s.to_vec()).unwrap()));
+            }
+            assert_eq!(got, items);
+        }
+
+        let out = dump_packed(command(&[], Some("irc.example.org"), "005", &["me"]), as_items(),
+            Packing::Params { trailer: IRCString::from("are supported by this server") }, 1.0);
+        let mut got = vec![];
+        for (_, line) in lines(&out) {
+            assert!(line.len() <= MAX_LINE);
+            let params = after(line, b":irc.example.org 005 me ");
+            let params = params.strip_suffix(b" :are supported by this server").unwrap();
+            let params: Vec<&[u8]> = params.split(|b| *b == b' ').collect();
+            // me, the items and the trailer
+            assert!(params.len() + 2 <= MAX_PARAMS);
+            got.extend(params.into_iter().map(|s| String::from_utf8(s.to_vec()).unwrap()));
+        }
+        assert_eq!(got, items);
+    }
+}
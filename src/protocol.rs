@@ -1,7 +1,11 @@
-use tokio::sync::mpsc;
+use std::{sync::{Arc, OnceLock}, time::SystemTime};
+
+use bytes::Bytes;
+use tokio::{sync::mpsc, time::Instant};
 
 use crate::user::UserID;
 use crate::room::RoomID;
+use crate::{caps::{Cap, CapSet}, parse, tags::{self, Tag}};
 
 // A Vec<u8> that might be a valid string in UTF-8, but no one should bet on that.
 // (IRC operates on bytestrings, not UTF-8 strings.)
@@ -20,6 +24,18 @@ impl IRCString {
     }
 }
 
+impl From<&str> for IRCString {
+    fn from(s: &str) -> Self {
+        IRCString::new(s.as_bytes().to_vec())
+    }
+}
+
+impl std::fmt::Display for IRCString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        String::from_utf8_lossy(&self.bytes).fmt(f)
+    }
+}
+
 impl std::fmt::Debug for IRCString {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match std::str::from_utf8(&self.bytes) {
@@ -29,42 +45,203 @@ impl std::fmt::Debug for IRCString {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Command {
+    pub tags: Vec<Tag>,
     pub pfx: Option<IRCString>,
     pub cmd: IRCString,
     pub args: Vec<IRCString>,
 }
 
+// Who waits on whose mailbox:
+//
+// - Rooms never wait on anyone. What they send users goes through a Relay
+//   (see room.rs), which is its own task, and which falls back on the room's
+//   LagPolicy if a member can't keep up.
+// - Users only ever wait on rooms, and only to join or leave (or say they're
+//   away, logged in as someone, or known by a new nick). Since rooms don't
+//   wait, that can't go in a circle.
+// - Everything else (one user to another, chat into a room) is try_send. If
+//   the mailbox is full we drop the message and tell whoever sent it.
+//
+// Mailboxes are sized in Config. A connection that can't keep up with what
+// arrives in its mailbox ends up over its sendq and is disconnected for it.
+
 #[derive(Clone)]
 pub enum U2R {  // user to room
-    Kill {},
     Join { 
         user: UserID,
-        user_mailbox: mpsc::Sender<(RoomID, R2U)>,
+        user_mailbox: mpsc::Sender<ToUser>,
+        nick: IRCString,
+        // their JOIN, as everyone will see it
+        relayed: Arc<Relayed>,
+        // comes back in Joined, so an answer to an old Join can't pass for a new one
+        ticket: u64,
     },
-    Part { user: UserID },
-    Privmsg {
+    Part { 
         user: UserID,
-        message: IRCString,
-    }
+        relayed: Arc<Relayed>,
+    },
+    // PRIVMSG, NOTICE or TAGMSG
+    Message {
+        user: UserID,
+        relayed: Arc<Relayed>,
+    },
+    // AWAY, with or without a message
+    Away {
+        user: UserID,
+        relayed: Arc<Relayed>,
+    },
+    // ACCOUNT, on login or logout
+    Account {
+        user: UserID,
+        relayed: Arc<Relayed>,
+    },
+    // NICK, once they're registered
+    Nick {
+        user: UserID,
+        nick: IRCString,
+        // nick!user@host, from now on
+        prefix: IRCString,
+        relayed: Arc<Relayed>,
+    },
+    // the user's task died without saying goodbye (if they were here, they're not now)
+    Lost { user: UserID },
 } 
 
 #[derive(Clone)]
 pub enum R2U {
-    Join { user: UserID },
-    Part { user: UserID },
-    Privmsg {
+    Join {
+        user: UserID,
+        relayed: Arc<Relayed>,
+    },
+    // the first thing whoever just joined hears, instead of their copy of the
+    // Join, so the member list can't overtake it
+    Joined {
+        ticket: u64,
+        relayed: Arc<Relayed>,
+        nicks: Vec<IRCString>,
+        // for rooms that replay what was said before you got there
+        history: Vec<Arc<Relayed>>,
+    },
+    Part {
+        user: UserID,
+        relayed: Arc<Relayed>,
+    },
+    Away {
         user: UserID,
-        message: IRCString,
+        relayed: Arc<Relayed>,
+    },
+    Account {
+        user: UserID,
+        relayed: Arc<Relayed>,
+    },
+    Nick {
+        user: UserID,
+        relayed: Arc<Relayed>,
+    },
+    // the room was reaped before it got to your Join: look it up again
+    Gone,
+    // the room shut down (or its task died) with you in it: it's gone, and
+    // you're not in it anymore
+    Closed { reason: &'static str },
+    Message {
+        user: UserID,
+        relayed: Arc<Relayed>,
+    },
+    // the member fell behind the room: `missed` messages were skipped,
+    // and the last `replayed` of those are about to be resent
+    Lagged { missed: u64, replayed: u64 },
+    // the member fell behind the room and the room gave up on them
+    Desynced,
+}
+
+// A message being passed along from one client to others. It gets its time and
+// msgid once, up front, so everyone sees the same ones. It's serialized at most
+// once per set of tags a recipient can ask for, and everyone who wants it that
+// way writes the same bytes.
+#[derive(Debug)]
+pub struct Relayed {
+    pub command: Command,
+    pub time: SystemTime,
+    pub msgid: IRCString,
+    // counts up with everything its sender relays (0 for the server's own, and
+    // for history read back from disk)
+    pub seq: u64,
+    // indexed by wire_index()
+    wires: [OnceLock<Bytes>; 16],
+}
+
+impl Relayed {
+    // `received` is when the client's command came in (or just now, for messages the server makes up)
+    pub fn new(command: Command, received: Instant) -> Arc<Relayed> {
+        Relayed::numbered(command, received, 0)
     }
+
+    // One of a user's, the `seq`th thing they've relayed.
+    pub fn numbered(command: Command, received: Instant, seq: u64) -> Arc<Relayed> {
+        Arc::new(Relayed {
+            command,
+            time: tags::wall_clock(received),
+            msgid: tags::new_msgid(),
+            seq,
+            wires: Default::default(),
+        })
+    }
+
+    // One we passed along before, from history saved to disk.
+    pub fn restore(command: Command, time: SystemTime, msgid: IRCString) -> Arc<Relayed> {
+        Arc::new(Relayed { command, time, msgid, seq: 0, wires: Default::default() })
+    }
+
+    // TAGMSG is nothing but tags, so it's only for clients that take them.
+    pub fn visible_to(&self, caps: CapSet) -> bool {
+        self.command.cmd.bytes != b"TAGMSG" || caps.has(Cap::MessageTags)
+    }
+
+    pub fn wire(&self, caps: CapSet) -> Bytes {
+        let (time, msgid) = (caps.has(Cap::ServerTime), caps.has(Cap::MessageTags));
+        self.wires[wire_index(caps)].get_or_init(|| {
+            let mut command = self.command.clone();
+            // (the client tags it came with, and who the sender's logged in as:
+            // nothing else it might have picked up goes out)
+            command.tags.retain(|t| match &t.key.bytes[..] {
+                [b'+', ..] => msgid,
+                b"account" => caps.has(Cap::AccountTag),
+                _ => false
+            });
+            // JOINs are made extended (`#room account :realname`), and cut down for everyone else
+            if command.cmd.bytes == b"JOIN" && !caps.has(Cap::ExtendedJoin) { command.args.truncate(1); }
+            if time { command.tags.push(Tag::new("time", tags::server_time(self.time))); }
+            if msgid { command.tags.push(Tag::new("msgid", self.msgid.clone())); }
+            parse::dump(command, 0.0).data
+        }).clone()
+    }
+}
+
+// Which of Relayed.wires is for clients with `caps`.
+fn wire_index(caps: CapSet) -> usize {
+    [Cap::ServerTime, Cap::MessageTags, Cap::AccountTag, Cap::ExtendedJoin].iter().enumerate()
+        .map(|(i, c)| (caps.has(*c) as usize) << i)
+        .sum()
 }
 
 pub enum U2U {
-    Privmsg { message: IRCString }
+    // PRIVMSG, NOTICE or TAGMSG
+    Message { relayed: Arc<Relayed> }
+}
+
+// directory to user
+#[derive(Clone)]
+pub enum D2U {
+    // someone took a nick you MONITOR
+    Online { user: UserID, nick: IRCString },
+    // and let go of it
+    Offline { nick: IRCString },
 }
 
 pub enum ToUser {
     Room { room_id: RoomID, message: R2U },
-    User { nick: IRCString, message: U2U }
+    User { message: U2U },
+    Directory { message: D2U },
 }
\ No newline at end of file
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use tokio::time::Instant;
+
+use crate::{parse, protocol::{Command, IRCString}, sock::MessageOut, tags::{self, Tag}};
+
+// Labels longer than this are ignored, and the command goes unlabeled.
+const MAX_LABEL: usize = 64;
+
+pub fn valid_label(label: &IRCString) -> bool {
+    !label.bytes.is_empty() && label.bytes.len() <= MAX_LABEL
+}
+
+// `+ref` and `-ref`: what the BATCH lines that open and close a batch say.
+pub fn batch_ends(reference: &IRCString) -> (IRCString, IRCString) {
+    let mut start = b"+".to_vec();
+    start.extend(&reference.bytes);
+    let mut end = b"-".to_vec();
+    end.extend(&reference.bytes);
+    (IRCString::new(start), IRCString::new(end))
+}
+
+// labeled-response: everything we send back because of one labeled command,
+// held until the command's done and then sent as a unit.
+pub struct Response {
+    id: u64,
+    label: IRCString,
+    // complete lines, minus their CRLFs
+    lines: Vec<Vec<u8>>,
+    deadline: Option<Instant>,
+    // things the command started that haven't been answered yet (JOINs
+    // waiting on their rooms). It isn't done until they are.
+    waiting: usize,
+}
+
+// The responses a user still has open.
+#[derive(Default)]
+pub struct Responses {
+    open: HashMap<u64, Response>,
+    // the one anything we send right now belongs to, if any
+    current: Option<u64>,
+    next: u64,
+}
+
+impl Responses {
+    // Start collecting for a new labeled command.
+    pub fn begin(&mut self, label: IRCString) {
+        let id = self.next;
+        self.next += 1;
+        self.open.insert(id, Response { id, label, lines: vec![], deadline: None, waiting: 0 });
+        self.current = Some(id);
+    }
+
+    // Go back to collecting for a response we held open (see wait).
+    pub fn resume(&mut self, id: Option<u64>) {
+        self.current = id.filter(|id| self.open.contains_key(id));
+    }
+
+    // Hang on to `msg` if it's part of a response. Otherwise, hand it back.
+    pub fn collect(&mut self, msg: MessageOut) -> Option<MessageOut> {
+        match self.current.and_then(|id| self.open.get_mut(&id)) {
+            Some(r) => { r.push(msg); None }
+            None => Some(msg)
+        }
+    }
+
+    // The current response has to wait for one more answer. Pass what this
+    // returns to resume and release once it arrives.
+    pub fn wait(&mut self) -> Option<u64> {
+        let r = self.current.and_then(|id| self.open.get_mut(&id))?;
+        r.waiting += 1;
+        Some(r.id)
+    }
+
+    // One of the answers `id` was waiting for came in (or never will). If that
+    // was the last one, and nobody's still adding to it, it's ready to send.
+    pub fn release(&mut self, id: Option<u64>) -> Option<Response> {
+        let id = id?;
+        let r = self.open.get_mut(&id)?;
+        r.waiting = r.waiting.saturating_sub(1);
+        if r.waiting > 0 || self.current == Some(id) { return None }
+        self.open.remove(&id)
+    }
+
+    // Stop collecting. If the response isn't waiting on anything, it's ready to send.
+    pub fn end(&mut self) -> Option<Response> {
+        let id = self.current.take()?;
+        if self.open.get(&id)?.waiting > 0 { return None }
+        self.open.remove(&id)
+    }
+}
+
+impl Response {
+    fn push(&mut self, msg: MessageOut) {
+        for line in msg.data.split(|b| *b == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if !line.is_empty() { self.lines.push(line.to_vec()); }
+        }
+        self.deadline = Some(self.deadline.map_or(msg.deadline, |d| d.min(msg.deadline)));
+    }
+
+    // No output at all gets an ACK, one line gets the label itself, and
+    // anything more goes in a batch with the reference `batch`. (Without one,
+    // we can't label that.)
+    pub fn finish(self, server: IRCString, batch: Option<IRCString>) -> MessageOut {
+        let label = Tag::new("label", self.label);
+        let mut out = vec![];
+        let from_server = |tags: Vec<Tag>, cmd: &str, args: Vec<IRCString>| {
+            parse::dump(Command { tags, pfx: Some(server.clone()), cmd: IRCString::from(cmd), args }, 0.0).data
+        };
+
+        match (self.lines.as_slice(), batch) {
+            ([], _) => { out.extend(from_server(vec![label], "ACK", vec![])) }
+            ([line], _) => {
+                tags::add_tags(&mut out, line, &[label]);
+                out.extend(b"\r\n");
+            }
+            (lines, Some(reference)) => {
+                let (start, end) = batch_ends(&reference);
+                out.extend(from_server(vec![label], "BATCH", vec![start, IRCString::from("labeled-response")]));
+                let tag = Tag::new("batch", reference);
+                for line in lines {
+                    // (what's already in a batch of its own stays there: we have its start)
+                    if tags::has_tag(line, b"batch") {
+                        out.extend(line);
+                    } else {
+                        tags::add_tags(&mut out, line, std::slice::from_ref(&tag));
+                    }
+                    out.extend(b"\r\n");
+                }
+                out.extend(from_server(vec![], "BATCH", vec![end]));
+            }
+            (lines, None) => {
+                for line in lines {
+                    out.extend(line);
+                    out.extend(b"\r\n");
+                }
+            }
+        }
+
+        MessageOut { deadline: self.deadline.unwrap_or_else(Instant::now), data: out.into() }
+    }
+}
@@ -1,78 +1,113 @@
-use std::collections::HashMap;
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}, time::SystemTime};
 
 use slotmap::new_key_type;
-use tokio::{sync::{broadcast, oneshot, mpsc, watch}, spawn};
+use tokio::{sync::{broadcast, oneshot, mpsc, watch}, spawn, time::Instant};
 
-use crate::{cancel::Cancel, protocol::{R2U, U2R}, user::UserID};
+use crate::{cancel::Cancel, supervise::{supervise, Actor}, protocol::{R2U, U2R, ToUser, IRCString, Command, Relayed}, user::UserID, config::{LagPolicy, Playback}, directory::Directory, history::{LogName, Point, Query}};
 
 new_key_type! { pub struct RoomID; }
 
 pub struct Room {
-    id: RoomID, mailbox: mpsc::Sender<U2R>,
-    cancel: Cancel,
+    mailbox: mpsc::Sender<U2R>,
+    // dropping the Room (out of the directory) is what tells the task to stop
+    _cancel: Cancel,
 
     snapshot: watch::Receiver<RoomSnapshot>,
 }
 
 pub struct RoomState {
-    id: RoomID, mailbox: mpsc::Sender<U2R>,
+    id: RoomID, 
+    name: IRCString,
+    // registered rooms stick around when they empty
+    registered: bool,
+    // what new members get replayed, if anything
+    playback: Option<Playback>,
     receive_cancel: oneshot::Receiver<()>,
     done: bool,
 
     // TODO: A layer of indirection between the mailbox and the users.
     // The channel coroutine should contain its own private state
     ingoing: mpsc::Receiver<U2R>,
-    outgoing: broadcast::Sender<R2U>,
+    outgoing: broadcast::Sender<Broadcast>,
     snapshot: watch::Sender<RoomSnapshot>,
+    directory: Directory,
+
+    // the last few broadcasts, so members who lag can catch up
+    next_seq: u64,
+    recent: Arc<Mutex<VecDeque<Broadcast>>>,
 
     members: HashMap<UserID, Member>,
 }
 
 #[derive(Clone)]
+struct Broadcast {
+    seq: u64,
+    msg: R2U,
+}
+
+// What other tasks can see of a room without asking it (WHO).
+#[derive(Clone, Default)]
 pub struct RoomSnapshot {
-    pub n_members: usize,
+    pub members: Arc<Vec<UserID>>,
 }
 
 struct Member {
-    cancel: Cancel,
-    mailbox: mpsc::Sender<(RoomID, R2U)> 
+    // stops their relay when they go
+    _cancel: Cancel,
+    // for telling them directly, once the relay's gone
+    mailbox: mpsc::Sender<ToUser>,
+    nick: IRCString,
+    prefix: IRCString,
 }
 
 impl Room {
-    pub fn new(id: RoomID) -> Self {
-        let (mailbox, ingoing) = mpsc::channel(1);
-        let (outgoing, _) = broadcast::channel(256);
+    pub fn new(id: RoomID, name: IRCString, registered: bool, playback: Option<Playback>, directory: Directory) -> Self {
+        let (mailbox, ingoing) = mpsc::channel(directory.config().rooms.mailbox);
+        let (outgoing, _) = broadcast::channel(directory.config().rooms.broadcast_capacity);
         let (cancel, receive_cancel) = Cancel::new();
-        let (set_snapshot, receive_snapshot) = watch::channel(RoomSnapshot { n_members: 0});
+        let (set_snapshot, receive_snapshot) = watch::channel(RoomSnapshot::default());
 
         let room_state = RoomState { 
-            id, mailbox: mailbox.clone(),
+            id,
+            name,
+            registered,
+            playback,
             receive_cancel,
             done: false,
 
             ingoing,
             outgoing,
             snapshot: set_snapshot,
+            directory: directory.clone(),
+
+            next_seq: 0,
+            recent: Arc::new(Mutex::new(VecDeque::new())),
 
             members: HashMap::new()
         };
 
-        tokio::spawn(async { room_state.flow() });
+        supervise(Actor::Room(id), directory, room_state.flow());
 
-        return Room {
-            id, mailbox,
-            cancel,
+        Room {
+            mailbox,
+            _cancel: cancel,
 
             snapshot: receive_snapshot,
         }
     }
+
+    pub fn get_mailbox(&self) -> mpsc::Sender<U2R> {
+        self.mailbox.clone()
+    }
+
+    pub fn get_snapshot(&self) -> watch::Receiver<RoomSnapshot> {
+        self.snapshot.clone()
+    }
 }
 
 impl RoomState {
     async fn flow(mut self) {
         loop {
-            self.touch_snapshot();
-
             if self.done { self.kill().await; return }
             let u2r = tokio::select! {
                 _ = &mut self.receive_cancel => { self.done = true; continue; }
@@ -83,83 +118,216 @@ impl RoomState {
             };
 
             match u2r {
-                U2R::Kill { } => { self.done = true; }
-                U2R::Join { user, user_mailbox } => { self.join(user, user_mailbox).await }
-                U2R::Part { user } => { self.part(user).await }
-                _ => { panic!("TODO"); }
+                U2R::Join { user, user_mailbox, nick, relayed, ticket } => { self.join(user, user_mailbox, nick, relayed, ticket).await }
+                U2R::Part { user, relayed } => { self.part(user, relayed).await }
+                U2R::Message { user, relayed } => { self.message(user, relayed).await }
+                U2R::Away { user, relayed } => { self.away(user, relayed).await }
+                U2R::Account { user, relayed } => { self.account(user, relayed).await }
+                U2R::Nick { user, nick, prefix, relayed } => { self.nick(user, nick, prefix, relayed).await }
+                U2R::Lost { user } => { self.lost(user).await }
             }
         }
     }
 
+    // (whenever the members change)
     fn touch_snapshot(&mut self) {
-        let _ = self.snapshot.send(RoomSnapshot { n_members: self.members.len() });
+        let members = Arc::new(self.members.keys().copied().collect());
+        let _ = self.snapshot.send(RoomSnapshot { members });
     }
 
-    async fn send(&mut self, user_id: UserID, msg: R2U) {
-        if let Some(member) = self.members.get(&user_id) {
-            match member.mailbox.send((self.id, msg)).await {
-                Ok(()) => {}
-                Err(_) => { /* TODO: Do we care? */ }
-            }
+    async fn broadcast(&mut self, msg: R2U) {
+        let b = Broadcast { seq: self.next_seq, msg };
+        self.next_seq += 1;
+
+        {
+            let mut recent = self.recent.lock().unwrap();
+            recent.push_back(b.clone());
+            while recent.len() > self.directory.config().rooms.resync_history { recent.pop_front(); }
         }
+
+        // an error just means nobody's listening right now
+        let _ = self.outgoing.send(b);
     }
 
-    async fn broadcast(&mut self, msg: R2U) {
-        match self.outgoing.send(msg) {
-            Ok(_) => {}
-            Err(_) => { self.done = true }
-        }
+    // A PART for a member who didn't send one themselves.
+    fn part_for(&self, prefix: IRCString, reason: Option<&str>) -> Arc<Relayed> {
+        let mut args = vec![self.name.clone()];
+        args.extend(reason.map(IRCString::from));
+        Relayed::new(Command { tags: vec![], pfx: Some(prefix), cmd: IRCString::from("PART"), args }, Instant::now())
     }
 
     pub async fn kill(&mut self) {
-        let members: Vec<UserID> = self.members.keys().cloned().collect();  // TODO: Avoid this
-        for user in members {
-            self.broadcast(R2U::Part { user }).await;
+        // (a broadcast would go nowhere: clearing the members cancels their relays)
+        let room_id = self.id;
+        for (_, member) in self.members.drain() {
+            spawn(async move {
+                let _ = member.mailbox.send(ToUser::Room { room_id, message: R2U::Closed { reason: "Channel closed" } }).await;
+            });
+        }
+        self.touch_snapshot();
+        self.done = true;
+
+        // anyone whose Join was already on its way in gets sent to look again
+        self.ingoing.close();
+        while let Some(u2r) = self.ingoing.recv().await {
+            if let U2R::Join { user_mailbox, .. } = u2r {
+                // this one can't be dropped, but we don't wait on users either
+                let room_id = self.id;
+                spawn(async move { let _ = user_mailbox.send(ToUser::Room { room_id, message: R2U::Gone }).await; });
+            }
         }
-        self.members.clear();
+    }
+
+    // The last member left: take ourselves out of the directory, so the next
+    // JOIN makes a new room. Anyone who found us just before this either finds
+    // our mailbox closed, or is told we're Gone when kill() drains it.
+    fn reap(&mut self) {
+        self.directory.room_reap(self.id);
         self.done = true;
     }
 
-    pub async fn join(&mut self, user: UserID, mailbox: mpsc::Sender<(RoomID, R2U)>) {
+    pub async fn join(&mut self, user: UserID, mailbox: mpsc::Sender<ToUser>, nick: IRCString, relayed: Arc<Relayed>, ticket: u64) {
         // send messages from channel to user 
         let (cancel, receive_cancel) = Cancel::new();
 
         if self.members.contains_key(&user) { return; }
 
-        let me_id = self.id;
+        let relay = Relay {
+            room_id: self.id,
+            to_user: mailbox.clone(),
+            recent: self.recent.clone(),
+            policy: self.directory.config().rooms.lag_policy,
+            // they only get what's broadcast from here on
+            next_seq: self.next_seq,
+        };
+        let prefix = relayed.command.pfx.clone().unwrap_or_else(|| nick.clone());
+        assert!(self.members.insert(user, Member {_cancel: cancel, mailbox, nick, prefix}).is_none());
+        self.touch_snapshot();
+
+        // the joiner gets theirs first thing from their relay, with the member list
+        // (and some history, maybe)
+        let nicks = self.members.values().map(|m| m.nick.clone()).collect();
+        let joined = R2U::Joined { ticket, relayed: relayed.clone(), nicks, history: self.playback() };
+
         let from_me = self.outgoing.subscribe();
-        let to_user = mailbox.clone();
-        spawn(async move {
-            tokio::pin!(receive_cancel);
-            tokio::pin!(from_me);
-            loop {
-                tokio::select! {
-                    _ = &mut receive_cancel => { return }
-                    x = from_me.recv() => match x {
-                        Ok(msg) => { 
-                            match to_user.send((me_id, msg)).await {
-                                Ok(()) => (),
-                                Err(_) => { return }
+        spawn(async move { relay.run(joined, from_me, receive_cancel).await });
+
+        self.broadcast(R2U::Join { user, relayed }).await
+    }
+
+    fn playback(&self) -> Vec<Arc<Relayed>> {
+        // (no more than a CHATHISTORY could ask for)
+        let max = self.directory.config().history.max_query;
+        let (query, limit) = match self.playback {
+            Some(Playback::Messages(n)) => (Query::Latest(None), n.min(max)),
+            // the latest of them, if there are too many
+            Some(Playback::Within(t)) => {
+                let since = SystemTime::now().checked_sub(t).unwrap_or(SystemTime::UNIX_EPOCH);
+                (Query::Latest(Some(Point::Time(since))), max)
+            }
+            None => return vec![]
+        };
+        self.directory.history().query(LogName::Room(&self.name), &query, limit)
+    }
+
+    pub async fn message(&mut self, user: UserID, relayed: Arc<Relayed>) {
+        if !self.members.contains_key(&user) { return; }
+        self.directory.history().record(LogName::Room(&self.name), relayed.clone());
+        self.broadcast(R2U::Message { user, relayed }).await
+    }
+
+    pub async fn part(&mut self, user: UserID, relayed: Arc<Relayed>) {
+        // (this stops their relay: they already showed themselves the PART)
+        if self.members.remove(&user).is_none() { return }
+        self.touch_snapshot();
+
+        self.broadcast(R2U::Part { user, relayed }).await;
+
+        if self.members.is_empty() && !self.registered { self.reap() }
+    }
+
+    pub async fn away(&mut self, user: UserID, relayed: Arc<Relayed>) {
+        if !self.members.contains_key(&user) { return; }
+        self.broadcast(R2U::Away { user, relayed }).await
+    }
+
+    pub async fn account(&mut self, user: UserID, relayed: Arc<Relayed>) {
+        if !self.members.contains_key(&user) { return; }
+        self.broadcast(R2U::Account { user, relayed }).await
+    }
+
+    pub async fn nick(&mut self, user: UserID, nick: IRCString, prefix: IRCString, relayed: Arc<Relayed>) {
+        match self.members.get_mut(&user) {
+            Some(member) => { member.nick = nick; member.prefix = prefix; }
+            None => return
+        }
+        self.broadcast(R2U::Nick { user, relayed }).await
+    }
+
+    pub async fn lost(&mut self, user: UserID) {
+        let prefix = match self.members.get(&user) {
+            Some(member) => member.prefix.clone(),
+            None => return
+        };
+        let relayed = self.part_for(prefix, Some("Connection lost"));
+        self.part(user, relayed).await
+    }
+}
+// Forwards a room's broadcasts to one member.
+struct Relay {
+    room_id: RoomID,
+    to_user: mpsc::Sender<ToUser>,
+    recent: Arc<Mutex<VecDeque<Broadcast>>>,
+    policy: LagPolicy,
+    next_seq: u64,
+}
+
+impl Relay {
+    async fn run(mut self, first: R2U, mut from_room: broadcast::Receiver<Broadcast>, mut cancel: oneshot::Receiver<()>) {
+        if !self.deliver(first).await { return }
+        loop {
+            let b = tokio::select! {
+                // once they've left, not one more message
+                biased;
+                _ = &mut cancel => { return }
+                x = from_room.recv() => match x {
+                    Ok(b) => b,
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        match self.policy {
+                            LagPolicy::Disconnect => { 
+                                let _ = self.deliver(R2U::Desynced).await;
+                                return 
                             }
+                            // we'll see the gap in sequence numbers on the next message
+                            LagPolicy::Resync => { continue }
                         }
-                        Err(broadcast::error::RecvError::Lagged(_)) => {
-                            // well that sucks. right now do nothing
-                            eprintln!("lagged!");
-                        }
-                        Err(broadcast::error::RecvError::Closed) => { return }
                     }
-                };
-            }
-        });
+                    Err(broadcast::error::RecvError::Closed) => { return }
+                }
+            };
+
+            if b.seq > self.next_seq && !self.resync(b.seq).await { return }
+            self.next_seq = b.seq + 1;
+            if !self.deliver(b.msg).await { return }
+        }
+    }
 
-        assert!(self.members.insert(user, Member {cancel, mailbox}).is_none());
+    // Replay whatever we still have from before `upto`, and own up to the rest.
+    async fn resync(&mut self, upto: u64) -> bool {
+        let replay: Vec<Broadcast> = {
+            let recent = self.recent.lock().unwrap();
+            recent.iter().filter(|b| b.seq >= self.next_seq && b.seq < upto).cloned().collect()
+        };
 
-        self.broadcast(R2U::Join { user }).await
+        let missed = upto - self.next_seq;
+        if !self.deliver(R2U::Lagged { missed, replayed: replay.len() as u64 }).await { return false }
+        for b in replay {
+            if !self.deliver(b.msg).await { return false }
+        }
+        true
     }
 
-    pub async fn part(&mut self, user: UserID) {
-        if !self.members.contains_key(&user) { return; }
-        self.broadcast(R2U::Part { user }).await;
-        assert!(self.members.remove(&user).is_some());
+    async fn deliver(&self, msg: R2U) -> bool {
+        self.to_user.send(ToUser::Room { room_id: self.room_id, message: msg }).await.is_ok()
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,85 @@
+use crate::{config::Config, protocol::IRCString};
+
+// SASL PLAIN, for logging in to an account (from the config file) with
+// AUTHENTICATE. The exchange itself lives in user.rs; this is the part that
+// doesn't need a connection.
+
+// AUTHENTICATE sends its payload in pieces this long; a shorter one (or `+`)
+// is the last
+pub const CHUNK: usize = 400;
+// and that's as many as we'll wait for
+pub const MAX_PAYLOAD: usize = 4 * CHUNK;
+
+// The account `payload` (still base64) logs in to, if the password's right.
+// PLAIN is `authzid NUL authcid NUL password`, and we don't let anyone log in
+// as somebody else, so the authzid is empty or the same as the authcid.
+pub fn check_plain(config: &Config, payload: &[u8]) -> Option<IRCString> {
+    let decoded = decode_base64(payload)?;
+    let mut fields = decoded.split(|b| *b == 0);
+    let (authzid, authcid, password) = (fields.next()?, fields.next()?, fields.next()?);
+    if fields.next().is_some() || (!authzid.is_empty() && authzid != authcid) { return None }
+
+    config.accounts.iter()
+        .find(|a| a.name.as_bytes() == authcid && a.password.as_bytes() == password)
+        .map(|a| IRCString::from(a.name.as_str()))
+}
+
+fn decode_base64(s: &[u8]) -> Option<Vec<u8>> {
+    fn sextet(b: u8) -> Option<u32> {
+        Some(match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None
+        } as u32)
+    }
+
+    if !s.len().is_multiple_of(4) { return None }
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    let n_quads = s.len() / 4;
+    for (i, quad) in s.chunks(4).enumerate() {
+        // (padding only at the very end)
+        let padding = quad.iter().rev().take_while(|b| **b == b'=').count();
+        if padding > 2 || (padding > 0 && i + 1 < n_quads) { return None }
+        let mut bits = 0;
+        for b in &quad[..4 - padding] { bits = bits << 6 | sextet(*b)?; }
+        bits <<= 6 * padding;
+        out.extend(&bits.to_be_bytes()[1..4 - padding]);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{AccountBlock, Config};
+
+    use super::{check_plain, decode_base64};
+
+    #[test]
+    fn base64() {
+        assert_eq!(decode_base64(b"").unwrap(), b"");
+        assert_eq!(decode_base64(b"Zg==").unwrap(), b"f");
+        assert_eq!(decode_base64(b"Zm8=").unwrap(), b"fo");
+        assert_eq!(decode_base64(b"Zm9v").unwrap(), b"foo");
+        assert_eq!(decode_base64(b"AGFsaWNlAGh1bnRlcjI=").unwrap(), b"\0alice\0hunter2");
+        for bad in [&b"Zm9"[..], b"Zm9v!A==", b"Zg==Zm9v", b"Z===", b"Zm 9"] {
+            assert!(decode_base64(bad).is_none(), "{}", String::from_utf8_lossy(bad));
+        }
+    }
+
+    #[test]
+    fn plain() {
+        let mut config = Config::default();
+        config.accounts.push(AccountBlock { name: "alice".to_string(), password: "hunter2".to_string() });
+
+        // \0alice\0hunter2, and alice\0alice\0hunter2
+        assert_eq!(&check_plain(&config, b"AGFsaWNlAGh1bnRlcjI=").unwrap().bytes[..], b"alice");
+        assert_eq!(&check_plain(&config, b"YWxpY2UAYWxpY2UAaHVudGVyMg==").unwrap().bytes[..], b"alice");
+        // the wrong password, someone else's authzid, and nobody we know
+        assert!(check_plain(&config, b"AGFsaWNlAGh1bnRlcjM=").is_none());
+        assert!(check_plain(&config, b"Ym9iAGFsaWNlAGh1bnRlcjI=").is_none());
+        assert!(check_plain(&config, b"AGJvYgBodW50ZXIy").is_none());
+    }
+}
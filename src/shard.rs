@@ -0,0 +1,70 @@
+use std::{collections::{HashMap, hash_map::{Entry, RandomState}}, hash::{BuildHasher, Hash}, sync::RwLock};
+
+const SHARDS: usize = 32;
+
+// A HashMap split across several RwLocks, so that lookups only contend with
+// writes to the same shard, and never with each other.
+//
+// Every lock is taken and released inside one of these methods, so none of
+// them can be held across an await.
+pub struct ShardedMap<K, V> {
+    hasher: RandomState,
+    shards: Box<[RwLock<HashMap<K, V>>]>,
+}
+
+impl<K: Hash + Eq, V: Clone> ShardedMap<K, V> {
+    pub fn new() -> Self {
+        ShardedMap {
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+        }
+    }
+
+    fn shard(&self, k: &K) -> &RwLock<HashMap<K, V>> {
+        &self.shards[self.hasher.hash_one(k) as usize % SHARDS]
+    }
+
+    pub fn get(&self, k: &K) -> Option<V> {
+        self.shard(k).read().unwrap().get(k).cloned()
+    }
+
+    pub fn insert(&self, k: K, v: V) -> Option<V> {
+        self.shard(&k).write().unwrap().insert(k, v)
+    }
+
+    pub fn remove(&self, k: &K) -> Option<V> {
+        self.shard(k).write().unwrap().remove(k)
+    }
+
+    // Everything, as of some moment or other (each shard is read separately).
+    pub fn values(&self) -> Vec<V> {
+        self.shards.iter().flat_map(|s| s.read().unwrap().values().cloned().collect::<Vec<V>>()).collect()
+    }
+
+    // Replace `k`'s value (or lack of one) with whatever `f` makes of it,
+    // all under the shard's lock.
+    pub fn update(&self, k: K, f: impl FnOnce(Option<V>) -> Option<V>) {
+        let mut shard = self.shard(&k).write().unwrap();
+        let old = shard.remove(&k);
+        if let Some(v) = f(old) { shard.insert(k, v); }
+    }
+
+    // Insert `v` unless `k` already has a value that `keep` approves of.
+    // Returns the value that was there if it stayed.
+    pub fn insert_unless(&self, k: K, v: V, keep: impl FnOnce(&V) -> bool) -> Result<(), V> {
+        match self.shard(&k).write().unwrap().entry(k) {
+            Entry::Occupied(o) if keep(o.get()) => Err(o.get().clone()),
+            Entry::Occupied(mut o) => { o.insert(v); Ok(()) }
+            Entry::Vacant(e) => { e.insert(v); Ok(()) }
+        }
+    }
+
+    // Remove `k`, but only if its value is still the one we expect.
+    pub fn remove_if(&self, k: &K, pred: impl FnOnce(&V) -> bool) -> Option<V> {
+        let mut shard = self.shard(k).write().unwrap();
+        match shard.get(k) {
+            Some(v) if pred(v) => shard.remove(k),
+            _ => None
+        }
+    }
+}
@@ -1,20 +1,44 @@
-use std::{net::SocketAddr};
+use std::{net::SocketAddr, time::Duration, sync::{Arc, atomic::{AtomicUsize, Ordering}}, collections::VecDeque, io::IoSlice};
 
-use tokio::{net::{TcpStream, tcp::{OwnedReadHalf, OwnedWriteHalf}}, io::{AsyncReadExt, AsyncWriteExt}, sync::{mpsc::{UnboundedSender, UnboundedReceiver}, oneshot}, time::Instant};
+use bytes::{Bytes, Buf};
+
+use tokio::{net::{TcpStream, tcp::{OwnedReadHalf, OwnedWriteHalf}}, io::{AsyncReadExt, AsyncWriteExt}, sync::{mpsc::{UnboundedSender, UnboundedReceiver}, oneshot}, task::JoinHandle, time::Instant};
 use tokio::sync::mpsc;
 
 use crate::{cancel::Cancel, protocol::IRCString};
 
 pub struct Sock {
     addr: SocketAddr, 
-    pub recv: UnboundedReceiver<MessageIn>,
-    pub send: UnboundedSender<MessageOut>,
+    pub recv: UnboundedReceiver<Result<MessageIn, LineTooLong>>,
+    pub send: SendQ,
     cancel1: Cancel,
     cancel2: Cancel,
+    writer: JoinHandle<()>,
+}
+
+// The output side of a Sock. Keeps count of the bytes that haven't hit the
+// wire yet, so a client that stops reading can't make us buffer forever.
+pub struct SendQ {
+    tx: UnboundedSender<MessageOut>,
+    queued: Arc<AtomicUsize>,
+    limit: usize,
 }
 
+#[derive(Debug)]
+pub struct SendQExceeded;
+
+// A client sent a line longer than MAX_LINE. We drop it, but tell them.
+#[derive(Debug)]
+pub struct LineTooLong;
+
+// 512 bytes, less the CRLF
+const MAX_LINE: usize = 510;
+// message-tags gives clients this much more for tags, @ and trailing space included
+const MAX_TAGS: usize = 4096;
+const READ_CHUNK: usize = 4096;
+
 impl Sock {
-    pub fn watch(socket: TcpStream, addr: SocketAddr) -> Sock {
+    pub fn watch(socket: TcpStream, addr: SocketAddr, sendq: usize) -> Sock {
         let (r, w) = socket.into_split();
 
         let (cancel1, receive_cancel1) = Cancel::new();
@@ -25,63 +49,93 @@ impl Sock {
             tokio::spawn(async { Sock::_read(r, tx, receive_cancel1).await });
             rx
         };
-        let send = {
+        let (send, writer) = {
             let (tx, rx) =  mpsc::unbounded_channel();
-            tokio::spawn(async { Sock::_write(w, rx, receive_cancel2).await });
-            tx
+            let queued = Arc::new(AtomicUsize::new(0));
+            let q2 = queued.clone();
+            let writer = tokio::spawn(async { Sock::_write(w, rx, q2, receive_cancel2).await });
+            (SendQ { tx, queued, limit: sendq }, writer)
         };
 
-        Sock { addr, recv, send, cancel1, cancel2 }
+        Sock { addr, recv, send, cancel1, cancel2, writer }
     }
 
-    async fn _read(mut read: OwnedReadHalf, tx: UnboundedSender<MessageIn>, mut cancel: oneshot::Receiver<()>) {
-        let mut buf = [0; 512];
-        let mut msg_in_progress = Vec::with_capacity(512);
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Stop reading right away, but give the writer up to `linger` to flush
+    // whatever is still queued (like a final ERROR) before it's cancelled.
+    pub async fn close(self, linger: Duration) {
+        let Sock { recv, send, cancel1, cancel2, writer, .. } = self;
+        drop(recv);
+        drop(cancel1);
+        drop(send);  // the writer flushes and exits once it sees this
+        let _ = tokio::time::timeout(linger, writer).await;
+        drop(cancel2);
+    }
+
+    async fn _read(mut read: OwnedReadHalf, tx: UnboundedSender<Result<MessageIn, LineTooLong>>, mut cancel: oneshot::Receiver<()>) {
+        let mut buf: Vec<u8> = Vec::with_capacity(READ_CHUNK);
+        // we gave up on the current line and are waiting for it to end
+        let mut discarding = false;
 
         loop {
-            let n = tokio::select! {
+            buf.reserve(READ_CHUNK);
+            tokio::select! {
                 _ = &mut cancel => { println!("socket dropped"); return }
-                x = read.read(&mut buf) => match x {
-                    Ok(n) if n == 0 => { 
+                x = read.read_buf(&mut buf) => match x {
+                    Ok(0) => { 
                         eprintln!("user quit: done");
                         return;
                     }
-                    Ok(n) => { n }
+                    Ok(_) => {}
                     Err(e) => {
                         eprintln!("failed to read from socket; err = {:?}", e);
                         return;
                     }
                 }
             };
+            let time = Instant::now();
 
-            for i in 0..n {
-                msg_in_progress.push(buf[i]);
-                if msg_in_progress.len() > 512 {
-                    eprintln!("message too long");
-                    return;
-                }
+            // lines can end in \r\n, \n or \r. the first two of those leave an empty line behind, which we skip
+            let mut start = 0;
+            while let Some(len) = buf[start..].iter().position(|b| *b == b'\r' || *b == b'\n') {
+                let line = &buf[start..start + len];
+                start += len + 1;
 
-                if msg_in_progress.ends_with(b"\r\n") {
-                    // get rid of the terminator
-                    msg_in_progress.pop();
-                    msg_in_progress.pop();
+                if discarding { discarding = false; continue }
 
-                    match tx.send(MessageIn { time: Instant::now(), data: IRCString::new(msg_in_progress) }) {
-                        Ok(()) => {}
-                        Err(e) => {
-                            eprintln!("failed to send to channel; err = {:?}", e);
-                        }
-                    }
-                    msg_in_progress = Vec::with_capacity(512);
+                let msg = if too_long(line) {
+                    Err(LineTooLong)
+                } else {
+                    let data: Vec<u8> = line.iter().copied().filter(|b| *b != 0).collect();
+                    if data.is_empty() { continue }
+                    Ok(MessageIn { time, data: IRCString::new(data) })
+                };
+                if let Err(e) = tx.send(msg) {
+                    eprintln!("failed to send to channel; err = {:?}", e);
+                    return
                 }
             }
+            buf.drain(..start);
+
+            // no terminator in sight and already too long: complain once, then throw it away as it comes in
+            if buf.len() > MAX_LINE + MAX_TAGS {
+                if !discarding {
+                    if tx.send(Err(LineTooLong)).is_err() { return }
+                    discarding = true;
+                }
+                buf.clear();
+            }
         }
     }
 
-    async fn _write(mut write: OwnedWriteHalf, mut tx: UnboundedReceiver<MessageOut>, cancel: oneshot::Receiver<()>) {
+    async fn _write(mut write: OwnedWriteHalf, mut tx: UnboundedReceiver<MessageOut>, queued: Arc<AtomicUsize>, cancel: oneshot::Receiver<()>) {
         let mut done = false;
         let mut send_at: Option<Instant> = None;
-        let mut write_buf = vec![];
+        // shared buffers, written straight out of the Bytes every other recipient is holding too
+        let mut write_buf: VecDeque<Bytes> = VecDeque::new();
 
         tokio::pin!(cancel);
 
@@ -96,22 +150,23 @@ impl Sock {
                         x = tx.recv() => match x {
                             Some(msg) => {
                                 send_at = Some(msg.deadline);
-                                write_buf.extend(msg.data.bytes)
+                                write_buf.push_back(msg.data)
                             }
                             None => { done = true; },  
                         }
                     }
                 }
                 Some(sa) if done || sa <= now => {
-                    match write.write_all(&write_buf).await {
+                    let n: usize = write_buf.iter().map(|b| b.len()).sum();
+                    match write_all_vectored(&mut write, &mut write_buf).await {
                         Ok(()) => {}
                         Err(e) => {
-                            eprintln!("failed to send to user: {:?} {:?}", write_buf, e);
+                            eprintln!("failed to send to user: {:?}", e);
                             return;
                         }
                     }
+                    queued.fetch_sub(n, Ordering::Relaxed);
                     send_at = None;
-                    write_buf.clear();
                 }
                 Some(sa) => {
                     // now figure out what to do
@@ -127,7 +182,7 @@ impl Sock {
                             match x {
                                 Some(msg) => {
                                     send_at = Some(sa.min(msg.deadline));
-                                    write_buf.extend(msg.data.bytes);
+                                    write_buf.push_back(msg.data);
                                 }
                                 None => { done = true; } // force any current messages to be sent
                             }
@@ -139,15 +194,66 @@ impl Sock {
     }
 }
 
+async fn write_all_vectored(write: &mut OwnedWriteHalf, bufs: &mut VecDeque<Bytes>) -> std::io::Result<()> {
+    while !bufs.is_empty() {
+        let slices: Vec<IoSlice> = bufs.iter().take(64).map(|b| IoSlice::new(b)).collect();
+        let mut n = write.write_vectored(&slices).await?;
+        if n == 0 { return Err(std::io::ErrorKind::WriteZero.into()) }
+
+        while n > 0 {
+            let front = bufs.front_mut().unwrap();
+            if front.len() <= n {
+                n -= front.len();
+                bufs.pop_front();
+            } else {
+                front.advance(n);
+                n = 0;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl SendQ {
+    pub fn send(&self, msg: MessageOut) -> Result<(), SendQExceeded> {
+        let n = msg.data.len();
+        let before = self.queued.fetch_add(n, Ordering::Relaxed);
+        if before + n > self.limit {
+            self.queued.fetch_sub(n, Ordering::Relaxed);
+            return Err(SendQExceeded)
+        }
+        // if the writer is gone, the reader has hung up too, and the owner will hear about it there
+        let _ = self.tx.send(msg);
+        Ok(())
+    }
+
+    // For last words (ERROR) that should go out even if the queue is full.
+    pub fn send_ignoring_limit(&self, msg: MessageOut) {
+        self.queued.fetch_add(msg.data.len(), Ordering::Relaxed);
+        let _ = self.tx.send(msg);
+    }
+}
+
 pub struct MessageIn {
     pub time: Instant,
     pub data: IRCString,
 }
 
+// Tags don't count against the usual limit: they have one of their own.
+fn too_long(line: &[u8]) -> bool {
+    let tags = if line.starts_with(b"@") {
+        line.iter().position(|b| *b == b' ').map_or(line.len(), |space| space + 1)
+    } else {
+        0
+    };
+    tags > MAX_TAGS || line.len() - tags > MAX_LINE
+}
+
 #[derive(Debug)]
 pub struct MessageOut {
     pub deadline: Instant,
-    pub data: IRCString,
+    // one or more complete lines, CRLFs and all
+    pub data: Bytes,
 }
 
 impl std::fmt::Debug for MessageIn {
@@ -155,4 +261,87 @@ impl std::fmt::Debug for MessageIn {
         // TODO: Don't allocate here
         f.debug_struct("MessageIn").field("data", &std::str::from_utf8(&self.data.bytes)).finish()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{io::AsyncWriteExt, net::{TcpListener, TcpStream}};
+
+    use super::{Sock, MAX_LINE, MAX_TAGS};
+
+    // What Sock makes of `chunks`, each of which arrives as a read of its own.
+    // A line too long to keep comes out as None.
+    async fn framed(chunks: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        client.set_nodelay(true).unwrap();
+        let (server, addr) = listener.accept().await.unwrap();
+        let mut sock = Sock::watch(server, addr, 1 << 20);
+
+        for chunk in chunks {
+            client.write_all(chunk).await.unwrap();
+            client.flush().await.unwrap();
+            // (long enough that the reader sees each chunk on its own)
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        client.shutdown().await.unwrap();
+
+        let mut out = vec![];
+        while let Some(msg) = sock.recv.recv().await {
+            out.push(msg.ok().map(|m| m.data.bytes));
+        }
+        out
+    }
+
+    fn lines(lines: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        lines.iter().map(|l| Some(l.to_vec())).collect()
+    }
+
+    #[tokio::test]
+    async fn read_splits_on_cr_lf_and_crlf() {
+        assert_eq!(framed(&[b"PING a\r\nPING b\nPING c\rPING d\r\n"]).await, lines(&[b"PING a", b"PING b", b"PING c", b"PING d"]));
+        // blank lines in between don't count
+        assert_eq!(framed(&[b"\r\n\r\nPING a\n\n\rPING b\r\n"]).await, lines(&[b"PING a", b"PING b"]));
+        // and without a terminator, a line never ends
+        assert_eq!(framed(&[b"PING a\r\nPING b"]).await, lines(&[b"PING a"]));
+    }
+
+    #[tokio::test]
+    async fn read_joins_lines_split_across_reads() {
+        assert_eq!(framed(&[b"PI", b"NG a\r", b"\nPING", b" b\r\n"]).await, lines(&[b"PING a", b"PING b"]));
+        // a CR at the end of one read and its LF at the start of the next are still one terminator
+        assert_eq!(framed(&[b"PING a\r", b"\n", b"\nPING b\r", b"\nPING c\n"]).await, lines(&[b"PING a", b"PING b", b"PING c"]));
+    }
+
+    #[tokio::test]
+    async fn read_strips_nul() {
+        assert_eq!(framed(&[b"PI\0NG \0a\0\r\n\0\r\n"]).await, lines(&[b"PING a"]));
+    }
+
+    #[tokio::test]
+    async fn read_rejects_long_lines() {
+        let longest = format!("PRIVMSG #a :{}", "x".repeat(MAX_LINE - "PRIVMSG #a :".len()));
+        let too_long = format!("{}x", longest);
+        assert_eq!(
+            framed(&[longest.as_bytes(), b"\r\n", too_long.as_bytes(), b"\r\nPING a\r\n"]).await,
+            vec![Some(longest.into_bytes()), None, Some(b"PING a".to_vec())]
+        );
+
+        // tags get a budget of their own
+        let tagged = format!("@+a={} PING a", "t".repeat(MAX_TAGS - "@+a= ".len()));
+        assert_eq!(framed(&[tagged.as_bytes(), b"\r\n"]).await, lines(&[tagged.as_bytes()]));
+        let tagged = format!("@+a={} PING a", "t".repeat(MAX_TAGS));
+        assert_eq!(framed(&[tagged.as_bytes(), b"\r\n"]).await, vec![None]);
+    }
+
+    #[tokio::test]
+    async fn read_discards_the_rest_of_an_endless_line() {
+        // one complaint, however many reads it takes, and nothing of it comes through
+        let chunk = vec![b'x'; MAX_LINE + MAX_TAGS];
+        assert_eq!(
+            framed(&[b"PING a\r\nPRIVMSG #a :", &chunk, &chunk, &chunk, b"still going\r\nPING b\r\n"]).await,
+            vec![Some(b"PING a".to_vec()), None, Some(b"PING b".to_vec())]
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use std::{any::Any, future::Future};
+
+use crate::{directory::Directory, protocol::{ToUser, R2U, U2R}, room::RoomID, user::UserID};
+
+// Who a task is, so we know who to tell when it dies.
+#[derive(Clone, Copy, Debug)]
+pub enum Actor {
+    User(UserID),
+    Room(RoomID),
+}
+
+// Spawn an actor's task and keep an eye on it. If it panics, say who it was,
+// take it out of the directory so nobody else sends it anything, and tell
+// whoever was counting on it.
+//
+// (An actor that finishes normally cleans up after itself.)
+pub fn supervise(actor: Actor, directory: Directory, task: impl Future<Output = ()> + Send + 'static) {
+    let handle = tokio::spawn(task);
+    tokio::spawn(async move {
+        let err = match handle.await {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+        // otherwise it was cancelled, which only happens when the runtime's going away
+        if !err.is_panic() { return }
+        let why = panic_message(err.into_panic());
+
+        match actor {
+            Actor::User(user_id) => {
+                let nick = directory.user_get_nick(user_id);
+                eprintln!("user {} ({:?}) panicked: {}", nick.map(|n| n.to_string()).unwrap_or_else(|| "*".to_string()), user_id, why);
+                let rooms = directory.user_get_rooms(user_id);
+                directory.user_drop(user_id);
+
+                // (rooms never wait on anyone, so they won't hold us up for long)
+                for room_id in rooms {
+                    if let Some(mailbox) = directory.room_get_mailbox(room_id) {
+                        let _ = mailbox.send(U2R::Lost { user: user_id }).await;
+                    }
+                }
+            }
+            Actor::Room(room_id) => {
+                let name = directory.room_get_name(room_id);
+                eprintln!("room {} ({:?}) panicked: {}", name.map(|n| n.to_string()).unwrap_or_else(|| "?".to_string()), room_id, why);
+                let users = directory.room_get_users(room_id);
+                directory.room_reap(room_id);
+
+                // users can be slow, so each gets told separately, and one
+                // that's backed up doesn't hold up the rest
+                for user_id in users {
+                    if let Some(mailbox) = directory.user_get_mailbox(user_id) {
+                        tokio::spawn(async move {
+                            let _ = mailbox.send(ToUser::Room { room_id, message: R2U::Closed { reason: "Channel closed unexpectedly" } }).await;
+                        });
+                    }
+                }
+            }
+        }
+    });
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(s) => *s,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(s) => s.to_string(),
+            Err(_) => "(no message)".to_string(),
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use std::{collections::hash_map::RandomState, hash::BuildHasher, sync::{OnceLock, atomic::{AtomicU64, Ordering}}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use tokio::time::Instant;
+
+use crate::{config::{ClientTags, TagFilter}, protocol::IRCString};
+
+// IRCv3 message tags: `@key=value;key2 ` in front of a line.
+// A tag with no value is the same as one with an empty value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    pub key: IRCString,
+    pub value: IRCString,
+}
+
+impl Tag {
+    pub fn new(key: &str, value: IRCString) -> Self {
+        Tag { key: IRCString::from(key), value }
+    }
+}
+
+// Everything between the @ and the space.
+pub fn parse_tags(src: &[u8]) -> Vec<Tag> {
+    src.split(|b| *b == b';').filter(|t| !t.is_empty()).map(|t| {
+        match t.iter().position(|b| *b == b'=') {
+            Some(eq) => Tag { key: IRCString::new(t[..eq].to_vec()), value: IRCString::new(unescape(&t[eq + 1..])) },
+            None => Tag { key: IRCString::new(t.to_vec()), value: IRCString::new(vec![]) },
+        }
+    }).collect()
+}
+
+// `@key=value;key2 `, or nothing if there aren't any.
+pub fn write_tags(out: &mut Vec<u8>, tags: &[Tag]) {
+    for (i, tag) in tags.iter().enumerate() {
+        out.push(if i == 0 { b'@' } else { b';' });
+        out.extend(&tag.key.bytes);
+        if !tag.value.bytes.is_empty() {
+            out.push(b'=');
+            escape(out, &tag.value.bytes);
+        }
+    }
+    if !tags.is_empty() { out.push(b' '); }
+}
+
+pub struct ClientTagsTooLong;
+
+// The `+` tags a client put on a message, less the ones we don't pass along.
+pub fn client_tags(config: &ClientTags, tags: &[Tag]) -> Result<Vec<Tag>, ClientTagsTooLong> {
+    let tags: Vec<Tag> = tags.iter().filter(|t| t.key.bytes.starts_with(b"+")).cloned().collect();
+    let mut written = vec![];
+    write_tags(&mut written, &tags);
+    if written.len().saturating_sub(2) > config.max_bytes { return Err(ClientTagsTooLong) }
+
+    Ok(tags.into_iter().filter(|t| {
+        let name = &t.key.bytes[1..];
+        match &config.filter {
+            TagFilter::Allow(names) => names.iter().any(|n| n.as_bytes() == name),
+            TagFilter::Deny(names) => !names.iter().any(|n| n.as_bytes() == name),
+        }
+    }).collect())
+}
+
+// For ISUPPORT, so clients know not to bother: CLIENTTAGDENY=a,b or CLIENTTAGDENY=*,-a,-b
+pub fn client_tag_deny(config: &ClientTags) -> Option<String> {
+    match &config.filter {
+        TagFilter::Deny(names) if names.is_empty() => None,
+        TagFilter::Deny(names) => Some(format!("CLIENTTAGDENY={}", names.join(","))),
+        TagFilter::Allow(names) => {
+            let allowed: String = names.iter().map(|n| format!(",-{}", n)).collect();
+            Some(format!("CLIENTTAGDENY=*{}", allowed))
+        }
+    }
+}
+
+// `line` (without its CRLF) with `extra` in front of whatever tags it already had.
+pub fn add_tags(out: &mut Vec<u8>, line: &[u8], extra: &[Tag]) {
+    write_tags(out, extra);
+    match line.strip_prefix(b"@") {
+        Some(rest) if !extra.is_empty() => {
+            out.pop();  // (the space after ours)
+            out.push(b';');
+            out.extend(rest);
+        }
+        _ => out.extend(line),
+    }
+}
+
+// The same, for every line in `data` (CRLFs and all).
+pub fn add_tags_to_lines(data: &[u8], extra: &[Tag]) -> Vec<u8> {
+    let mut out = vec![];
+    for line in data.split(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() { continue }
+        add_tags(&mut out, line, extra);
+        out.extend(b"\r\n");
+    }
+    out
+}
+
+// Whether `line` already has a `key` tag.
+pub fn has_tag(line: &[u8], key: &[u8]) -> bool {
+    let tags = match line.strip_prefix(b"@") {
+        Some(rest) => &rest[..rest.iter().position(|b| *b == b' ').unwrap_or(rest.len())],
+        None => return false
+    };
+    tags.split(|b| *b == b';').any(|t| t.split(|b| *b == b'=').next() == Some(key))
+}
+
+fn escape(out: &mut Vec<u8>, value: &[u8]) {
+    for &b in value {
+        match b {
+            b';' => out.extend(b"\\:"),
+            b' ' => out.extend(b"\\s"),
+            b'\\' => out.extend(b"\\\\"),
+            b'\r' => out.extend(b"\\r"),
+            b'\n' => out.extend(b"\\n"),
+            _ => out.push(b),
+        }
+    }
+}
+
+fn unescape(value: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(value.len());
+    let mut it = value.iter();
+    while let Some(&b) = it.next() {
+        if b != b'\\' { out.push(b); continue }
+        match it.next() {
+            Some(b':') => out.push(b';'),
+            Some(b's') => out.push(b' '),
+            Some(b'r') => out.push(b'\r'),
+            Some(b'n') => out.push(b'\n'),
+            Some(&other) => out.push(other),  // (including \\)
+            None => {}  // a lone backslash at the end just goes away
+        }
+    }
+    out
+}
+
+// What the wall clock said at `t`, which is a tokio Instant from some time ago.
+pub fn wall_clock(t: Instant) -> SystemTime {
+    SystemTime::now() - Instant::now().saturating_duration_since(t)
+}
+
+// For `time=`: ISO 8601 in UTC, to the millisecond, like 2011-10-19T16:40:51.620Z
+pub fn server_time(t: SystemTime) -> IRCString {
+    let since = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    let (y, m, d) = civil_from_days(days);
+    IRCString::from(format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        y, m, d, rem / 3600, rem / 60 % 60, rem % 60, since.subsec_millis()
+    ).as_str())
+}
+
+// The other way, for CHATHISTORY's `timestamp=`. The milliseconds are optional.
+pub fn parse_server_time(s: &[u8]) -> Option<SystemTime> {
+    let s = std::str::from_utf8(s).ok()?.strip_suffix('Z')?;
+    let (date, time) = s.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (y, m, d) = (date.next()??, date.next()??, date.next()??);
+    let (hms, millis) = match time.split_once('.') {
+        Some((hms, ms)) if ms.len() == 3 && ms.bytes().all(|b| b.is_ascii_digit()) => (hms, ms.parse::<u64>().ok()?),
+        Some(_) => return None,
+        None => (time, 0),
+    };
+    let mut hms = hms.splitn(3, ':').map(|p| p.parse::<u64>().ok());
+    let (h, min, sec) = (hms.next()??, hms.next()??, hms.next()??);
+    // (four-digit years only, which also keeps the arithmetic from overflowing)
+    if !(0..=9999).contains(&y) || !(1..=12).contains(&m) || !(1..=31).contains(&d) { return None }
+    if h > 23 || min > 59 || sec > 60 { return None }
+
+    let days = u64::try_from(days_from_civil(y, m, d)).ok()?;
+    let secs = days.checked_mul(86400)?.checked_add(h * 3600 + min * 60 + sec)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))?.checked_add(Duration::from_millis(millis))
+}
+
+// (year, month, day) to days since 1970-01-01, and back. Howard Hinnant's algorithms.
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + if m <= 2 { 1 } else { 0 }, m, d)
+}
+
+// For `msgid=`: a random number picked at startup (so IDs don't repeat across
+// restarts, or between servers) followed by a counter.
+pub fn new_msgid() -> IRCString {
+    static BOOT: OnceLock<u64> = OnceLock::new();
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    let boot = *BOOT.get_or_init(|| RandomState::new().hash_one(SystemTime::now()));
+    IRCString::from(format!("{:016x}{:x}", boot, NEXT.fetch_add(1, Ordering::Relaxed)).as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{parse_server_time, server_time};
+
+    #[test]
+    fn server_time_round_trips() {
+        for millis in [0, 1_319_042_451_620, 951_782_400_000, 4_102_444_799_999, 253_402_300_799_999] {
+            let t = UNIX_EPOCH + Duration::from_millis(millis);
+            assert_eq!(parse_server_time(&server_time(t).bytes), Some(t), "{}", server_time(t));
+        }
+        assert_eq!(&server_time(UNIX_EPOCH + Duration::from_millis(1_319_042_451_620)).bytes[..], b"2011-10-19T16:40:51.620Z");
+    }
+
+    #[test]
+    fn parse_server_time_without_millis() {
+        assert_eq!(parse_server_time(b"2011-10-19T16:40:51Z"), Some(UNIX_EPOCH + Duration::from_secs(1_319_042_451)));
+    }
+
+    #[test]
+    fn parse_server_time_rejects_nonsense() {
+        for s in [
+            &b"300000000000-01-01T00:00:00Z"[..],
+            b"10000-01-01T00:00:00Z",
+            b"-1-01-01T00:00:00Z",
+            b"1969-12-31T23:59:59Z",
+            b"2011-13-19T16:40:51Z",
+            b"2011-10-19T24:00:00Z",
+            b"2011-10-19T16:40:51.62Z",
+            b"2011-10-19T16:40:51.+62Z",
+            b"2011-10-19T16:40:51",
+            b"2011-10-19",
+            b"",
+        ] {
+            assert_eq!(parse_server_time(s), None, "{}", String::from_utf8_lossy(s));
+        }
+    }
+}
@@ -1,16 +1,17 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::{Duration, SystemTime}, sync::{Arc, atomic::Ordering}};
 
 use slotmap::new_key_type;
-use tokio::{sync::{mpsc, oneshot}, time::Instant};
+use tokio::{sync::{mpsc::{self, error::TrySendError}, oneshot}, time::Instant};
 
-use crate::{room::RoomID, protocol::{R2U, U2R, IRCString, Command, ToUser, U2U}, cancel::Cancel, sock::{Sock, MessageIn, MessageOut}, parse, directory::Directory};
+use crate::{room::RoomID, supervise::{supervise, Actor}, caps::{Cap, CapSet}, protocol::{U2R, R2U, D2U, IRCString, Command, ToUser, U2U, Relayed}, casemap::Folded, cancel::Cancel, sock::{Sock, MessageIn, MessageOut, SendQExceeded, LineTooLong}, parse::{self, Packing}, response::{self, Response, Responses}, history::{LogName, Owner, Point, Query}, names, sasl, tags::{self, Tag, ClientTagsTooLong}, encoding::{self, InputEncoding}, directory::{Directory, ChangeNickError}, config::ConnectionClass, flood::{Throttle, ExcessFlood}};
 
 new_key_type! { pub struct UserID; }
 
 pub struct User {
-    id: UserID, mailbox: mpsc::Sender<ToUser>,
+    mailbox: mpsc::Sender<ToUser>,
 
-    cancel: Cancel,
+    // dropping the User (out of the directory) is what tells the task to stop
+    _cancel: Cancel,
 }
 
 impl User {
@@ -24,14 +25,39 @@ pub struct UserState {
     receive_cancel: oneshot::Receiver<()>,
     done: bool,
     directory: Directory,
+    class: Arc<ConnectionClass>,
+    encoding: InputEncoding,
 
     sock: Sock,
     ingoing: mpsc::Receiver<ToUser>,
-
+    liveness: Liveness,
+    throttle: Throttle,
 
     id_card: UserIDCard,
+    // done with NICK, USER, and CAP (if they started on it)
+    registered: bool,
+    caps: CapSet,
+    // between CAP LS/REQ and CAP END, registration waits
+    cap_negotiating: bool,
+    // an AUTHENTICATE PLAIN that's waiting on the rest of its payload
+    sasl: Option<Vec<u8>>,
+    // when the command we're handling came in
+    received: Instant,
+    // labeled-response
+    responses: Responses,
+    // for BATCH references
+    next_batch: u64,
 
     memberships: HashMap<RoomID, Membership>,
+    // numbers our Joins, see Membership
+    next_ticket: u64,
+    // the latest AWAY, ACCOUNT or NICK we've passed on from each sender, by
+    // its seq and msgid (see first_copy)
+    notified: HashMap<UserID, (u64, IRCString)>,
+    // the seq of the last thing we relayed
+    relayed_seq: u64,
+    // MONITOR, the way they typed it
+    monitoring: HashMap<Folded, IRCString>,
 }
 
 #[derive(Debug)]
@@ -39,53 +65,237 @@ pub struct UserIDCard {
     nick: Option<IRCString>,
     user: Option<IRCString>,
     realname: Option<IRCString>,
+    oper: bool,
+    // the AWAY message, while they're away
+    away: Option<IRCString>,
+    // who they're logged in as, if anyone
+    account: Option<IRCString>,
+}
+
+// What other users can find out about a user with WHOIS and WHO. Each user
+// keeps theirs up to date in the directory.
+#[derive(Debug)]
+pub struct Profile {
+    pub user: IRCString,
+    pub host: IRCString,
+    pub realname: IRCString,
+    pub oper: bool,
+    pub away: Option<IRCString>,
+    pub account: Option<IRCString>,
 }
 
+// How many senders we remember AWAYs, ACCOUNTs and NICKs from before forgetting the ones that are gone.
+const NOTIFIED: usize = 64;
+
+
+// how many nicks one ISON or USERHOST can ask about (the rest are ignored)
+const ISON_NICKS: usize = 64;
+const USERHOST_NICKS: usize = 5;
+
+// how many targets one PRIVMSG, NOTICE or TAGMSG can have (TARGMAX)
+const MAX_TARGETS: usize = 4;
+
+// how many times JOIN looks a room up again if it's reaped out from under us
+const JOIN_ATTEMPTS: usize = 3;
 
 pub struct Membership {
+    name: IRCString,
     mailbox: mpsc::Sender<U2R>,
+    // our JOIN, until the room answers the Join with this ticket
+    ticket: u64,
+    pending_join: Option<Arc<Relayed>>,
+    // the labeled response the JOIN's answer belongs in
+    response: Option<u64>,
+}
+
+// When we last heard from the client, and whether we're waiting on a PONG.
+struct Liveness {
+    connected_at: Instant,
+    last_seen: Instant,
+    ping_sent: Option<Instant>,
 }
 
 impl User {
-    pub fn new(id: UserID, sock: Sock, directory: Directory) -> Self {
-        let (mailbox, ingoing) = mpsc::channel(1);
+    pub fn new(id: UserID, sock: Sock, class: Arc<ConnectionClass>, encoding: InputEncoding, directory: Directory) -> Self {
+        let (mailbox, ingoing) = mpsc::channel(class.mailbox);
         let (cancel, receive_cancel) = Cancel::new();
+        let now = Instant::now();
+        let throttle = Throttle::new(&class.flood, now);
 
         let user_state = UserState {
             id, mailbox: mailbox.clone(),
             receive_cancel,
             done: false,
-            directory,
+            directory: directory.clone(),
+            class,
+            encoding,
 
             sock,
             ingoing,
+            liveness: Liveness { connected_at: now, last_seen: now, ping_sent: None },
+            throttle,
 
-            id_card: UserIDCard { nick: None, user: None, realname: None },
+            id_card: UserIDCard { nick: None, user: None, realname: None, oper: false, away: None, account: None },
+            registered: false,
+            caps: CapSet::default(),
+            cap_negotiating: false,
+            sasl: None,
+            received: now,
+            responses: Responses::default(),
+            next_batch: 0,
 
             memberships: HashMap::new(),
+            next_ticket: 0,
+            notified: HashMap::new(),
+            relayed_seq: 0,
+            monitoring: HashMap::new(),
         };
 
-        tokio::spawn(async { user_state.flow().await });
+        supervise(Actor::User(id), directory, user_state.flow());
 
-        return User { 
-            id, mailbox,
-            cancel
+        User { 
+            mailbox,
+            _cancel: cancel
         }
     }
 }
 
 impl UserState {
     fn my_nick(&self) -> IRCString {
-        self.id_card.nick.as_ref().map(|x| x.clone()).unwrap_or_else(|| IRCString::new(b"unknown".to_vec()))
+        self.id_card.nick.clone().unwrap_or_else(|| IRCString::from("*"))
+    }
+    // nick!user@host, the way other people see us
+    fn my_prefix(&self) -> IRCString {
+        let mut pfx = self.my_nick().bytes;
+        pfx.push(b'!');
+        pfx.extend(self.id_card.user.as_ref().map_or(&b"*"[..], |u| &u.bytes));
+        pfx.push(b'@');
+        pfx.extend(self.my_host().bytes);
+        IRCString::new(pfx)
+    }
+
+    fn my_host(&self) -> IRCString {
+        IRCString::from(self.sock.addr().ip().to_string().as_str())
+    }
+
+    // Tell the directory what WHOIS and WHO should say about us now.
+    fn publish_profile(&self) {
+        let card = &self.id_card;
+        self.directory.user_set_profile(self.id, Profile {
+            user: card.user.clone().unwrap_or_else(|| IRCString::from("*")),
+            host: self.my_host(),
+            realname: card.realname.clone().unwrap_or_else(|| IRCString::from("")),
+            oper: card.oper,
+            away: card.away.clone(),
+            account: card.account.clone(),
+        });
+    }
+
+    // for account-tag
+    fn account_tag(&self) -> Option<Tag> {
+        self.id_card.account.clone().map(|a| Tag::new("account", a))
+    }
+
+    // Whose our end of a conversation's history is.
+    fn history_owner(&self) -> Owner {
+        self.directory.history().owner(self.id, self.id_card.account.as_ref())
+    }
+
+    // A DM we got through to `recipient`, for both our history and theirs.
+    fn record_conversation(&self, recipient: UserID, target: &IRCString, relayed: &Arc<Relayed>) {
+        let history = self.directory.history();
+        history.record(LogName::Conversation(&self.history_owner(), target), relayed.clone());
+        // (talking to ourselves is just the one conversation)
+        if recipient == self.id { return }
+        let account = self.directory.user_get_profile(recipient).and_then(|p| p.account.clone());
+        history.record(LogName::Conversation(&history.owner(recipient, account.as_ref()), &self.my_nick()), relayed.clone());
+    }
+
+    fn is_channel_name(&self, name: &IRCString) -> bool {
+        name.bytes.first().is_some_and(|b| self.directory.config().names.chantypes.as_bytes().contains(b))
+    }
+
+    fn server_name(&self) -> IRCString {
+        IRCString::from(self.directory.config().server_name.as_str())
+    }
+
+    fn send(&mut self, msg: MessageOut) {
+        if let Some(msg) = self.responses.collect(msg) {
+            self.send_now(msg);
+        }
     }
+
+    // (past any response we're collecting)
+    fn send_now(&mut self, msg: MessageOut) {
+        if let Err(SendQExceeded) = self.sock.send.send(msg) {
+            self.directory.metrics().sendq_exceeded.fetch_add(1, Ordering::Relaxed);
+            self.disconnect("SendQ exceeded");
+        }
+    }
+
+    fn send_from_server(&mut self, cmd: &str, args: Vec<IRCString>) {
+        let msg = parse::dump(Command {
+            tags: vec![],
+            pfx: Some(self.server_name()),
+            cmd: IRCString::from(cmd),
+            args,
+        }, 0.0);
+        self.send(msg);
+    }
+
+    // Say goodbye and hang up. The ERROR still gets flushed when the socket closes.
+    fn disconnect(&mut self, reason: &str) {
+        if self.done { return }
+        self.sock.send.send_ignoring_limit(parse::dump(Command {
+            tags: vec![],
+            pfx: None,
+            cmd: IRCString::from("ERROR"),
+            args: vec![IRCString::from(format!("Closing link ({})", reason).as_str())],
+        }, 0.0));
+        self.done = true;
+    }
+
     async fn flow(mut self) {
         loop {
-            if self.done { self.kill().await; return }
+            if self.done { 
+                self.kill().await; 
+                self.directory.user_drop(self.id);
+                self.sock.close(Duration::from_secs(1)).await;
+                return 
+            }
+
+            let mut wake_at = self.liveness_deadline();
+            if let Some(due) = self.throttle.next_due() { wake_at = wake_at.min(due) }
 
             tokio::select! {
-                _ = &mut self.receive_cancel => { self.done = true; continue; },
+                _ = &mut self.receive_cancel => { 
+                    // the directory let go of us, which only happens when the server is going down
+                    let reason = self.directory.config().shutdown_message.clone();
+                    self.disconnect(&reason);
+                    continue; 
+                },
+                _ = tokio::time::sleep_until(wake_at) => { 
+                    let now = Instant::now();
+                    self.check_liveness(now);
+                    self.run_due_commands(now).await;
+                }
                 tcp = self.sock.recv.recv() => match tcp {
-                    Some(t) => { 
+                    Some(Err(LineTooLong)) => {
+                        self.liveness.last_seen = Instant::now();
+                        self.liveness.ping_sent = None;
+                        let nick = self.my_nick();
+                        self.send_from_server("417", vec![nick, IRCString::from("Input line was too long")]);
+                    }
+                    Some(Ok(mut t)) => { 
+                        // anything at all from the client counts as a sign of life
+                        self.liveness.last_seen = t.time;
+                        self.liveness.ping_sent = None;
+
+                        if !encoding::decode(self.encoding, &mut t.data.bytes) {
+                            self.reject_invalid_utf8(&t);
+                            continue 
+                        }
+
                         let cmd = match parse::parse(&t) {
                             Some(cmd) => cmd,
                             None => {
@@ -96,12 +306,15 @@ impl UserState {
 
                         println!("received: {:?}", cmd);
 
-                        if !self.id_card.is_complete() {
-                            self.handle_user_prelogin(cmd).await;
-                        } else {
-                            self.handle_user(cmd).await;
+                        if self.flood_exempt() && self.throttle.next_due().is_none() {
+                            self.received = t.time;
+                            self.handle_command(cmd).await;
+                            continue
+                        }
+                        match self.throttle.push(&self.class.flood, cmd, t.time) {
+                            Ok(()) => { self.run_due_commands(Instant::now()).await; }
+                            Err(ExcessFlood) => { self.disconnect("Excess Flood"); }
                         }
-
                     }
                     None => { self.done = true; continue; }
                 },
@@ -115,105 +328,1393 @@ impl UserState {
         }
     }
 
+    fn reject_invalid_utf8(&mut self, t: &MessageIn) {
+        let cmd = parse::parse(t).map(|c| c.cmd).unwrap_or_else(|| IRCString::from("*"));
+        self.send_from_server("FAIL", vec![cmd, IRCString::from("INVALID_UTF8"), IRCString::from("Message rejected, it contained invalid UTF-8")]);
+    }
+
+    async fn handle_command(&mut self, cmd: Command) {
+        if self.caps.has(Cap::LabeledResponse) {
+            let label = cmd.tags.iter().find(|t| t.key.bytes == b"label").map(|t| &t.value);
+            if let Some(label) = label.filter(|l| response::valid_label(l)) {
+                self.responses.begin(label.clone());
+            }
+        }
+
+        match cmd.cmd.bytes.as_slice() {
+            b"PING" => { self.handle_ping(cmd) }
+            b"PONG" => { /* the read loop already noted that they're alive */ }
+            _ if !self.registered => { self.handle_user_prelogin(cmd).await; }
+            _ => { self.handle_user(cmd).await; }
+        }
+        self.end_response();
+    }
+
+    // Done adding to the current labeled response. Send it, unless it's still waiting on something.
+    fn end_response(&mut self) {
+        if let Some(r) = self.responses.end() { self.send_response(r) }
+    }
+
+    fn send_response(&mut self, r: Response) {
+        let reference = self.batch_ref();
+        let msg = r.finish(self.server_name(), reference);
+        self.send_now(msg);
+    }
+
+    // A new BATCH reference, if they can take batches at all.
+    fn batch_ref(&mut self) -> Option<IRCString> {
+        if !self.caps.has(Cap::Batch) { return None }
+        self.next_batch += 1;
+        Some(IRCString::from(format!("{:x}", self.next_batch).as_str()))
+    }
+
+    // Sends `msgs` in a BATCH of `kind`, if they can take one.
+    fn send_batch(&mut self, kind: &str, mut args: Vec<IRCString>, msgs: Vec<MessageOut>) {
+        let reference = match self.batch_ref() {
+            Some(r) => r,
+            None => {
+                for msg in msgs { self.send(msg) }
+                return
+            }
+        };
+        let (start, end) = response::batch_ends(&reference);
+        args.insert(0, IRCString::from(kind));
+        args.insert(0, start);
+        self.send_from_server("BATCH", args);
+        let tag = [Tag::new("batch", reference)];
+        for msg in msgs {
+            let data = tags::add_tags_to_lines(&msg.data, &tag);
+            self.send(MessageOut { deadline: msg.deadline, data: data.into() });
+        }
+        self.send_from_server("BATCH", vec![end]);
+    }
+
+    fn flood_exempt(&self) -> bool {
+        let fc = &self.class.flood;
+        fc.exempt || (fc.exempt_opers && self.id_card.oper)
+    }
+
+    // Run whatever commands the throttle has let through by now.
+    async fn run_due_commands(&mut self, now: Instant) {
+        while let Some((received, cmd)) = self.throttle.pop_due(now) {
+            if self.done { return }
+            self.received = received;
+            self.handle_command(cmd).await;
+        }
+    }
+
+    fn liveness_deadline(&self) -> Instant {
+        let class = &self.class;
+        let l = &self.liveness;
+
+        let mut deadline = match l.ping_sent {
+            Some(sent) => sent + class.ping_timeout,
+            None => l.last_seen + class.ping_interval,
+        };
+        if !self.registered {
+            deadline = deadline.min(l.connected_at + class.registration_timeout);
+        }
+        deadline
+    }
+
+    fn check_liveness(&mut self, now: Instant) {
+        let class = &self.class;
+        let l = &self.liveness;
+
+        if !self.registered && now >= l.connected_at + class.registration_timeout {
+            self.disconnect("Registration timeout");
+            return
+        }
+
+        match l.ping_sent {
+            Some(sent) if now >= sent + class.ping_timeout => {
+                let waited = (now - l.last_seen).as_secs();
+                self.disconnect(&format!("Ping timeout: {} seconds", waited));
+            }
+            Some(_) => { /* still waiting */ }
+            None if now >= l.last_seen + class.ping_interval => {
+                self.liveness.ping_sent = Some(now);
+                self.send(parse::dump(Command { 
+                    tags: vec![],
+                    pfx: None,
+                    cmd: IRCString::from("PING"),
+                    args: vec![self.server_name()],
+                }, 0.0));
+            }
+            None => {}
+        }
+    }
+
+    fn handle_ping(&mut self, cmd: Command) {
+        match cmd.args.last() {
+            Some(token) => {
+                let token = token.clone();
+                self.send_from_server("PONG", vec![self.server_name(), token]);
+            }
+            None => {
+                let nick = self.my_nick();
+                self.send_from_server("409", vec![nick, IRCString::from("No origin specified")]);
+            }
+        }
+    }
+
     async fn handle_user_prelogin(&mut self, cmd: Command) {
-        assert!(!self.id_card.is_complete());
+        assert!(!self.registered);
         match (cmd.cmd.bytes.as_slice(), cmd.args.as_slice()) {
-            (b"CAP", _) => { /* do nothing, we don't support capability negotiation */ }
-            (b"NICK", [name]) => { 
+            (b"CAP", _) => { self.handle_cap(&cmd) }
+            (b"AUTHENTICATE", _) => { self.authenticate(&cmd.args).await }
+            (b"NICK", []) => {
+                self.send_from_server("431", vec![IRCString::from("*"), IRCString::from("No nickname given")]);
+            }
+            (b"NICK", [name, ..]) => { 
+                if !names::valid_nick(self.directory.config(), name) {
+                    let name = name.clone();
+                    self.send_from_server("432", vec![IRCString::from("*"), name, IRCString::from("Erroneous nickname")]);
+                    return
+                }
                 self.id_card.nick.replace(name.clone()); 
             }
             (b"USER", [user, _, _, realname]) => { 
-                self.id_card.user.replace(user.clone());
+                let user = match names::clean_username(&self.directory.config().names, user) {
+                    Some(u) => u,
+                    None => {
+                        self.send_from_server("468", vec![IRCString::from("*"), IRCString::from("Your username is not valid")]);
+                        return
+                    }
+                };
+                self.id_card.user.replace(user);
                 self.id_card.realname.replace(realname.clone());
             }
-            _ => { panic!("TODO"); }
+            (b"USER", _) => {
+                self.send_from_server("461", vec![IRCString::from("*"), IRCString::from("USER"), IRCString::from("Not enough parameters")]);
+            }
+            _ => {
+                self.send_from_server("451", vec![IRCString::from("*"), IRCString::from("You have not registered")]);
+            }
         }
 
-        if self.id_card.is_complete() {
+        if self.id_card.is_complete() && !self.cap_negotiating {
+            // (before the nick, so MONITORs see the whole thing when it shows up)
+            self.publish_profile();
             match self.directory.user_change_nick(self.id, self.id_card.nick.clone()) {
                 Ok(()) => { /* we're good */ }
-                Err(_) => {
-                    // TODO: Send a message
-                    self.id_card.nick = None;
+                Err(ChangeNickError::NickInUse) => {
+                    let nick = self.id_card.nick.take().unwrap();
+                    self.send_from_server("433", vec![IRCString::from("*"), nick, IRCString::from("Nickname is already in use")]);
                     return
                 }
             }
 
-            let _ = self.sock.send.send(MessageOut { 
-                deadline: Instant::now(), 
-                data: IRCString::new(b"001 Nyeogmi :Welcome to the server (test)\r\n".to_vec())
-            });
-            let _ = self.sock.send.send(MessageOut { 
-                deadline: Instant::now(), 
-                data: IRCString::new(b"002 barf\r\n".to_vec())
-            });
-            let _ = self.sock.send.send(MessageOut { 
-                deadline: Instant::now(), 
-                data: IRCString::new(b"003 barf\r\n".to_vec())
-            });
-            let _ = self.sock.send.send(MessageOut { 
-                deadline: Instant::now(), 
-                data: IRCString::new(b"004 barf\r\n".to_vec())
-            });
+            self.registered = true;
+            self.send_welcome();
         } 
     }
 
+    fn handle_cap(&mut self, cmd: &Command) {
+        let nick = self.my_nick();
+        let mut sub = match cmd.args.first() {
+            Some(s) => s.clone(),
+            None => {
+                self.send_from_server("461", vec![nick, IRCString::from("CAP"), IRCString::from("Not enough parameters")]);
+                return
+            }
+        };
+        sub.upper_inplace();
+
+        match (sub.bytes.as_slice(), &cmd.args[1..]) {
+            (b"LS", _) => {
+                if !self.registered { self.cap_negotiating = true; }
+                let all = Cap::ALL.iter().map(|c| c.name()).collect::<Vec<_>>().join(" ");
+                self.send_from_server("CAP", vec![nick, sub, IRCString::from(all.as_str())]);
+            }
+            (b"LIST", _) => {
+                let on = self.caps.iter().map(|c| c.name()).collect::<Vec<_>>().join(" ");
+                self.send_from_server("CAP", vec![nick, sub, IRCString::from(on.as_str())]);
+            }
+            (b"REQ", [wanted, ..]) => {
+                if !self.registered { self.cap_negotiating = true; }
+                // all or nothing
+                let mut caps = self.caps;
+                let ok = wanted.bytes.split(|b| *b == b' ').filter(|w| !w.is_empty()).all(|w| {
+                    let (remove, name) = match w.strip_prefix(b"-") {
+                        Some(name) => (true, name),
+                        None => (false, w),
+                    };
+                    match Cap::from_name(name) {
+                        Some(c) if remove => { caps.remove(c); true }
+                        Some(c) => { caps.insert(c); true }
+                        None => false
+                    }
+                });
+                if ok { self.caps = caps; }
+                let reply = IRCString::from(if ok { "ACK" } else { "NAK" });
+                self.send_from_server("CAP", vec![nick, reply, wanted.clone()]);
+            }
+            (b"END", _) => { self.cap_negotiating = false; }
+            _ => {
+                self.send_from_server("410", vec![nick, sub, IRCString::from("Invalid CAP command")]);
+            }
+        }
+    }
+
+    fn send_welcome(&mut self) {
+        let nick = self.my_nick();
+        let server = self.directory.config().server_name.clone();
+        let version = concat!("batircd-", env!("CARGO_PKG_VERSION"));
+
+        self.send_from_server("001", vec![nick.clone(), IRCString::from(format!("Welcome to the Internet Relay Network {}", nick).as_str())]);
+        self.send_from_server("002", vec![nick.clone(), IRCString::from(format!("Your host is {}, running version {}", server, version).as_str())]);
+        let created = tags::server_time(self.directory.started());
+        self.send_from_server("003", vec![nick.clone(), IRCString::from(format!("This server was created {}", created).as_str())]);
+        self.send_from_server("004", vec![nick.clone(), IRCString::from(server.as_str()), IRCString::from(version), IRCString::from("o")]);
+
+        let tokens = self.isupport().into_iter().map(|t| IRCString::from(t.as_str())).collect();
+        let msg = parse::dump_packed(Command {
+            tags: vec![],
+            pfx: Some(self.server_name()),
+            cmd: IRCString::from("005"),
+            args: vec![nick],
+        }, tokens, Packing::Params { trailer: IRCString::from("are supported by this server") }, 0.0);
+        self.send(msg);
+    }
+
+    fn isupport(&self) -> Vec<String> {
+        let config = self.directory.config();
+        let mut tokens = vec![
+            format!("CASEMAPPING={}", config.casemapping.isupport_name()),
+            format!("CHANTYPES={}", config.names.chantypes),
+            format!("NICKLEN={}", config.names.nicklen),
+            format!("USERLEN={}", config.names.userlen),
+            format!("CHANNELLEN={}", config.names.channellen),
+        ];
+        if let InputEncoding::RequireUtf8 = self.encoding {
+            tokens.push("UTF8ONLY".to_string());
+        }
+        tokens.push(format!("CHATHISTORY={}", config.history.max_query));
+        tokens.push("MSGREFTYPES=timestamp,msgid".to_string());
+        tokens.push(format!("TARGMAX=PRIVMSG:{},NOTICE:{},TAGMSG:{}", MAX_TARGETS, MAX_TARGETS, MAX_TARGETS));
+        tokens.push("WHOX".to_string());
+        tokens.push(format!("MONITOR={}", self.class.monitor));
+        tokens.extend(tags::client_tag_deny(&config.client_tags));
+        tokens
+    }
+
     async fn handle_user(&mut self, cmd: Command) {
         match (cmd.cmd.bytes.as_slice(), cmd.args.as_slice()) {
-            (b"PRIVMSG", [name, msg]) => {
-                if name.bytes.starts_with(b"#")  {
-                    panic!("TODO")
+            (b"JOIN", [names, ..]) => {
+                for name in names.bytes.split(|b| *b == b',') {
+                    self.join(IRCString::new(name.to_vec())).await;
+                }
+            }
+            (b"PART", [names, rest @ ..]) => {
+                let message = rest.first().cloned();
+                for name in names.bytes.split(|b| *b == b',') {
+                    let name = IRCString::new(name.to_vec());
+                    let room = self.directory.room_by_name(&name).filter(|r| self.memberships.contains_key(r));
+                    match room {
+                        Some(room_id) => { self.part(room_id, message.clone()).await }
+                        None => {
+                            let nick = self.my_nick();
+                            self.send_from_server("442", vec![nick, name, IRCString::from("You're not on that channel")]);
+                        }
+                    }
+                }
+            }
+            (b"CAP", _) => { self.handle_cap(&cmd) }
+            (b"PRIVMSG" | b"NOTICE", [targets, text, ..]) if !text.bytes.is_empty() => {
+                self.message_all(&cmd, targets, Some(text.clone()));
+            }
+            (b"PRIVMSG" | b"NOTICE", []) => {
+                let nick = self.my_nick();
+                self.send_from_server("411", vec![nick, IRCString::from(format!("No recipient given ({})", cmd.cmd).as_str())]);
+            }
+            (b"PRIVMSG" | b"NOTICE", _) => {
+                let nick = self.my_nick();
+                self.send_from_server("412", vec![nick, IRCString::from("No text to send")]);
+            }
+            (b"TAGMSG", [targets, ..]) => {
+                self.message_all(&cmd, targets, None);
+            }
+            (b"CHATHISTORY", _) => { self.chathistory(&cmd) }
+            (b"AWAY", args) => {
+                let message = args.first().filter(|m| !m.bytes.is_empty()).cloned();
+                self.away(message).await;
+            }
+            (b"WHOIS", []) => {
+                let nick = self.my_nick();
+                self.send_from_server("431", vec![nick, IRCString::from("No nickname given")]);
+            }
+            // (the first of two is a server, and there's only us)
+            (b"WHOIS", [.., target]) => { self.whois(target.clone()) }
+            (b"ISON", nicks @ [_, ..]) => { self.ison(nicks) }
+            (b"USERHOST", nicks @ [_, ..]) => { self.userhost(nicks) }
+            (b"WHO", [mask, rest @ ..]) => { self.who(mask.clone(), rest.first()) }
+            (b"MONITOR", [sub, rest @ ..]) => { self.monitor(sub.clone(), rest.first()) }
+            (b"JOIN" | b"PART" | b"TAGMSG" | b"WHO" | b"MONITOR" | b"ISON" | b"USERHOST", []) | (b"OPER", [] | [_]) => {
+                let nick = self.my_nick();
+                self.send_from_server("461", vec![nick, cmd.cmd.clone(), IRCString::from("Not enough parameters")]);
+            }
+            (b"OPER", [name, password, ..]) => {
+                let nick = self.my_nick();
+                let ok = self.directory.config().opers.iter().any(|o| {
+                    o.name.as_bytes() == name.bytes && o.password.as_bytes() == password.bytes
+                });
+                if ok {
+                    self.id_card.oper = true;
+                    self.publish_profile();
+                    self.send_from_server("381", vec![nick, IRCString::from("You are now an IRC operator")]);
                 } else {
-                    match self.directory.user_nick_to_mailbox(name) {
-                        Some(mb) => { 
-                            match mb.send(ToUser::User { nick: self.my_nick(), message: U2U::Privmsg { 
-                                message: msg.clone(),
-                            }}).await {
-                                Ok(()) => { /* */ }
-                                Err(_) => { panic!("TODO") }
-                            }
+                    self.send_from_server("464", vec![nick, IRCString::from("Password incorrect")]);
+                }
+            }
+            (b"NICK", []) => {
+                let nick = self.my_nick();
+                self.send_from_server("431", vec![nick, IRCString::from("No nickname given")]);
+            }
+            (b"NICK", [name, ..]) => { self.change_nick(name.clone()).await }
+            (b"AUTHENTICATE", _) => { self.authenticate(&cmd.args).await }
+            _ => {
+                let nick = self.my_nick();
+                self.send_from_server("421", vec![nick, cmd.cmd.clone(), IRCString::from("Unknown command")]);
+            }
+        }
+    }
+
+    async fn join(&mut self, name: IRCString) {
+        let nick = self.my_nick();
+        if !names::valid_channel(&self.directory.config().names, &name) {
+            self.send_from_server("476", vec![nick, name, IRCString::from("Bad Channel Mask")]);
+            return
+        }
+
+        // the room can be reaped between us finding it and our Join arriving,
+        // in which case we just look it up (or make it) again
+        for _ in 0..JOIN_ATTEMPTS {
+            let (room_id, room_name, mailbox) = match self.directory.room_get_or_create(&name) {
+                Some(r) => r,
+                None => return  // shutting down
+            };
+            if self.memberships.contains_key(&room_id) { return }
+
+            let ticket = self.next_ticket;
+            self.next_ticket += 1;
+            // (extended-join's, which Relayed trims for everyone else)
+            let account = self.id_card.account.clone().unwrap_or_else(|| IRCString::from("*"));
+            let realname = self.id_card.realname.clone().unwrap_or_else(|| IRCString::from(""));
+            let relayed = self.relayed("JOIN", vec![room_name.clone(), account, realname]);
+            let join = U2R::Join { user: self.id, user_mailbox: self.mailbox.clone(), nick: nick.clone(), relayed: relayed.clone(), ticket };
+            // (before it's sent, so if the room dies with our Join inside, we hear about it)
+            self.directory.user_enter_room(self.id, room_id);
+            if mailbox.send(join).await.is_ok() {
+                // we're in as far as we're concerned: the room will answer with Joined (or Gone)
+                let response = self.responses.wait();
+                self.memberships.insert(room_id, Membership { name: room_name, mailbox, ticket, pending_join: Some(relayed), response });
+                return
+            }
+            self.directory.user_leave_room(self.id, room_id);
+        }
+        self.send_from_server("437", vec![nick, name, IRCString::from("Channel is temporarily unavailable")]);
+    }
+
+    // We're out as soon as we say so: if we JOIN again right away, that goes
+    // to the back of the room's mailbox, after this.
+    async fn part(&mut self, room_id: RoomID, message: Option<IRCString>) {
+        let m = match self.memberships.get(&room_id) {
+            Some(m) => m,
+            None => return
+        };
+        let mut args = vec![m.name.clone()];
+        args.extend(message);
+        let relayed = self.relayed("PART", args);
+
+        self.send_room(room_id, U2R::Part { user: self.id, relayed: relayed.clone() }).await;
+        if let Some(m) = self.memberships.remove(&room_id) {
+            self.directory.user_leave_room(self.id, room_id);
+            // (the JOIN's response gets no more than it already has)
+            if let Some(r) = self.responses.release(m.response) { self.send_response(r) }
+            // if they're quick, they'd otherwise see themselves leave without ever arriving
+            if let Some(join) = m.pending_join {
+                self.send_relayed(&join);
+            }
+            self.send_relayed(&relayed);
+        }
+    }
+
+    // Something from this client that's going to other people.
+    fn relayed(&mut self, cmd: &str, args: Vec<IRCString>) -> Arc<Relayed> {
+        let command = Command { tags: self.account_tag().into_iter().collect(), pfx: Some(self.my_prefix()), cmd: IRCString::from(cmd), args };
+        self.number(command)
+    }
+
+    fn number(&mut self, command: Command) -> Arc<Relayed> {
+        self.relayed_seq += 1;
+        Relayed::numbered(command, self.received, self.relayed_seq)
+    }
+
+    // `a,b,c`: each target gets a message of its own (and flood control
+    // charges for each).
+    fn message_all(&mut self, msg: &Command, targets: &IRCString, text: Option<IRCString>) {
+        let targets: Vec<IRCString> = targets.bytes.split(|b| *b == b',')
+            .filter(|t| !t.is_empty())
+            .map(|t| IRCString::new(t.to_vec()))
+            .collect();
+        if targets.len() > MAX_TARGETS {
+            if msg.cmd.bytes != b"NOTICE" {
+                let nick = self.my_nick();
+                self.send_from_server("407", vec![nick, msg.args[0].clone(), IRCString::from("Too many recipients")]);
+            }
+            return
+        }
+        for target in targets {
+            self.message(msg, target, text.clone());
+        }
+    }
+
+    // PRIVMSG, NOTICE and TAGMSG (which has no text), to one target. Nothing
+    // ever answers a NOTICE, including us.
+    fn message(&mut self, msg: &Command, target: IRCString, text: Option<IRCString>) {
+        let cmd = msg.cmd.clone();
+        let notice = cmd.bytes == b"NOTICE";
+        let mut tags = match tags::client_tags(&self.directory.config().client_tags, &msg.tags) {
+            Ok(tags) => tags,
+            Err(ClientTagsTooLong) => {
+                let nick = self.my_nick();
+                self.send_from_server("417", vec![nick, IRCString::from("Input line was too long")]);
+                return
+            }
+        };
+        tags.extend(self.account_tag());
+        let mut args = vec![target.clone()];
+        args.extend(text);
+        let relayed = self.number(Command { 
+            tags, 
+            pfx: Some(self.my_prefix()), 
+            cmd: cmd.clone(), 
+            args 
+        });
+
+        if self.is_channel_name(&target) {
+            let room = self.directory.room_by_name(&target).filter(|r| self.memberships.contains_key(r));
+            match room {
+                Some(room_id) => {
+                    if self.try_send_room(room_id, U2R::Message { user: self.id, relayed: relayed.clone() }) {
+                        self.echo(&relayed);
+                    } else if !notice {
+                        self.target_busy(cmd, target);
+                    }
+                }
+                None if notice => {}
+                None => {
+                    let nick = self.my_nick();
+                    self.send_from_server("404", vec![nick, target, IRCString::from("Cannot send to channel")]);
+                }
+            }
+        } else {
+            let recipient = self.directory.user_by_nick(&target);
+            let sent = recipient.and_then(|r| self.directory.user_get_mailbox(r)).map(|mb| {
+                mb.try_send(ToUser::User { message: U2U::Message { relayed: relayed.clone() } })
+            });
+            match (sent, recipient) {
+                (Some(Ok(())), Some(recipient)) => {
+                    self.record_conversation(recipient, &target, &relayed);
+                    self.echo(&relayed);
+                    if cmd.bytes == b"PRIVMSG" { self.send_away_of(target) }
+                }
+                _ if notice => {}
+                (Some(Err(TrySendError::Full(_))), _) => { self.target_busy(cmd, target) }
+                // (if their mailbox is closed, they're on their way out)
+                _ => {
+                    let nick = self.my_nick();
+                    self.send_from_server("401", vec![nick, target, IRCString::from("No such nick/channel")]);
+                }
+            }
+        }
+    }
+
+    async fn away(&mut self, message: Option<IRCString>) {
+        let nick = self.my_nick();
+        match &message {
+            Some(_) => self.send_from_server("306", vec![nick, IRCString::from("You have been marked as being away")]),
+            None => self.send_from_server("305", vec![nick, IRCString::from("You are no longer marked as being away")]),
+        }
+        if self.id_card.away == message { return }
+        self.id_card.away = message.clone();
+        self.publish_profile();
+
+        // for away-notify
+        let relayed = self.relayed("AWAY", message.into_iter().collect());
+        let rooms: Vec<RoomID> = self.memberships.keys().cloned().collect();
+        for room_id in rooms {
+            self.send_room(room_id, U2R::Away { user: self.id, relayed: relayed.clone() }).await;
+        }
+    }
+
+    // SASL PLAIN, one AUTHENTICATE at a time: the mechanism, then the payload
+    // (or `*` to give up).
+    async fn authenticate(&mut self, args: &[IRCString]) {
+        let nick = self.my_nick();
+        let arg = match args.first() {
+            Some(a) => a,
+            None => {
+                self.send_from_server("461", vec![nick, IRCString::from("AUTHENTICATE"), IRCString::from("Not enough parameters")]);
+                return
+            }
+        };
+        if arg.bytes == b"*" {
+            self.sasl = None;
+            self.send_from_server("906", vec![nick, IRCString::from("SASL authentication aborted")]);
+            return
+        }
+
+        let payload = match self.sasl.as_mut() {
+            Some(p) => p,
+            None if self.id_card.account.is_some() => {
+                self.send_from_server("907", vec![nick, IRCString::from("You have already authenticated using SASL")]);
+                return
+            }
+            None if !arg.bytes.eq_ignore_ascii_case(b"PLAIN") => {
+                self.send_from_server("908", vec![nick.clone(), IRCString::from("PLAIN"), IRCString::from("are available SASL mechanisms")]);
+                self.send_from_server("904", vec![nick, IRCString::from("SASL authentication failed")]);
+                return
+            }
+            None => {
+                self.sasl = Some(vec![]);
+                self.send_from_server("AUTHENTICATE", vec![IRCString::from("+")]);
+                return
+            }
+        };
+
+        if arg.bytes != b"+" { payload.extend(&arg.bytes) }
+        if arg.bytes.len() > sasl::CHUNK || payload.len() > sasl::MAX_PAYLOAD {
+            self.sasl = None;
+            self.send_from_server("905", vec![nick, IRCString::from("SASL message too long")]);
+            return
+        }
+        // (a whole chunk means there's more)
+        if arg.bytes.len() == sasl::CHUNK { return }
+
+        let payload = self.sasl.take().unwrap_or_default();
+        match sasl::check_plain(self.directory.config(), &payload) {
+            Some(account) => {
+                self.set_account(Some(account.clone())).await;
+                let text = IRCString::from(format!("You are now logged in as {}", account).as_str());
+                let prefix = self.my_prefix();
+                self.send_from_server("900", vec![nick.clone(), prefix, account, text]);
+                self.send_from_server("903", vec![nick, IRCString::from("SASL authentication successful")]);
+            }
+            None => {
+                self.send_from_server("904", vec![nick, IRCString::from("SASL authentication failed")]);
+            }
+        }
+    }
+
+    // Log in as `account`, or out with None, and tell everyone in a room with us.
+    async fn set_account(&mut self, account: Option<IRCString>) {
+        if self.id_card.account == account { return }
+        self.id_card.account = account.clone();
+        self.publish_profile();
+
+        // for account-notify
+        let relayed = self.relayed("ACCOUNT", vec![account.unwrap_or_else(|| IRCString::from("*"))]);
+        let rooms: Vec<RoomID> = self.memberships.keys().cloned().collect();
+        for room_id in rooms {
+            self.send_room(room_id, U2R::Account { user: self.id, relayed: relayed.clone() }).await;
+        }
+    }
+
+    // NICK, after registration: everyone in a room with us sees it.
+    async fn change_nick(&mut self, name: IRCString) {
+        let me = self.my_nick();
+        if !names::valid_nick(self.directory.config(), &name) {
+            self.send_from_server("432", vec![me, name, IRCString::from("Erroneous nickname")]);
+            return
+        }
+        if name == me { return }
+        match self.directory.user_change_nick(self.id, Some(name.clone())) {
+            Ok(()) => {}
+            Err(ChangeNickError::NickInUse) => {
+                self.send_from_server("433", vec![me, name, IRCString::from("Nickname is already in use")]);
+                return
+            }
+        }
+
+        // (from who we were)
+        let relayed = self.relayed("NICK", vec![name.clone()]);
+        self.id_card.nick = Some(name.clone());
+        self.send_relayed(&relayed);
+
+        let prefix = self.my_prefix();
+        let rooms: Vec<RoomID> = self.memberships.keys().cloned().collect();
+        for room_id in rooms {
+            let msg = U2R::Nick { user: self.id, nick: name.clone(), prefix: prefix.clone(), relayed: relayed.clone() };
+            self.send_room(room_id, msg).await;
+        }
+    }
+
+    // RPL_AWAY, if `nick` is away.
+    fn send_away_of(&mut self, nick: IRCString) {
+        let away = self.directory.user_by_nick(&nick)
+            .and_then(|u| self.directory.user_get_profile(u))
+            .and_then(|p| p.away.clone());
+        if let Some(away) = away {
+            let me = self.my_nick();
+            self.send_from_server("301", vec![me, nick, away]);
+        }
+    }
+
+    // Someone in a room with us went away or came back (or joined while
+    // away), or logged in or out, and we asked to hear about it with `cap`.
+    fn send_notify(&mut self, cap: Cap, sender: UserID, relayed: &Relayed) {
+        if self.caps.has(cap) && self.first_copy(sender, relayed) { self.send_relayed(relayed) }
+    }
+
+    // Whether we haven't seen this already from another room. (For anything a
+    // user sends every room they're in: AWAY, ACCOUNT and NICK.)
+    //
+    // Each room passes on its sender's in order, so the first copy of each
+    // arrives before anything they did after it: a copy numbered no higher
+    // than the last one we showed from them is one we've seen. (Their seq
+    // only counts up, which the wall clock they're timestamped by needn't.)
+    fn first_copy(&mut self, sender: UserID, relayed: &Relayed) -> bool {
+        match self.notified.get(&sender) {
+            Some((seq, msgid)) if *msgid == relayed.msgid || relayed.seq <= *seq => return false,
+            Some(_) => {}
+            None => if self.notified.len() >= NOTIFIED {
+                let directory = &self.directory;
+                self.notified.retain(|u, _| directory.user_get_mailbox(*u).is_some());
+            }
+        }
+        self.notified.insert(sender, (relayed.seq, relayed.msgid.clone()));
+        true
+    }
+
+    fn whois(&mut self, target: IRCString) {
+        let me = self.my_nick();
+        let found = self.directory.user_by_nick(&target)
+            .and_then(|u| Some((self.directory.user_get_nick(u)?, self.directory.user_get_profile(u)?)));
+        let (nick, profile) = match found {
+            Some(f) => f,
+            None => {
+                self.send_from_server("401", vec![me.clone(), target.clone(), IRCString::from("No such nick/channel")]);
+                self.send_from_server("318", vec![me, target, IRCString::from("End of /WHOIS list")]);
+                return
+            }
+        };
+        let server = self.server_name();
+
+        self.send_from_server("311", vec![me.clone(), nick.clone(), profile.user.clone(), profile.host.clone(), IRCString::from("*"), profile.realname.clone()]);
+        self.send_from_server("312", vec![me.clone(), nick.clone(), server, IRCString::from(concat!("batircd-", env!("CARGO_PKG_VERSION")))]);
+        if profile.oper {
+            self.send_from_server("313", vec![me.clone(), nick.clone(), IRCString::from("is an IRC operator")]);
+        }
+        if let Some(away) = &profile.away {
+            self.send_from_server("301", vec![me.clone(), nick.clone(), away.clone()]);
+        }
+        if let Some(account) = &profile.account {
+            self.send_from_server("330", vec![me.clone(), nick.clone(), account.clone(), IRCString::from("is logged in as")]);
+        }
+        self.send_from_server("318", vec![me, nick, IRCString::from("End of /WHOIS list")]);
+    }
+
+    // The nicks that are taken, the way their owners typed them. (Some clients
+    // put them all in one param.)
+    fn ison(&mut self, args: &[IRCString]) {
+        let me = self.my_nick();
+        let nicks = args.iter().flat_map(|a| a.bytes.split(|b| *b == b' ')).filter(|n| !n.is_empty()).take(ISON_NICKS);
+        let online: Vec<Vec<u8>> = nicks.filter_map(|n| {
+            let user = self.directory.user_by_nick(&IRCString::new(n.to_vec()))?;
+            Some(self.directory.user_get_nick(user)?.bytes)
+        }).collect();
+        self.send_from_server("303", vec![me, IRCString::new(online.join(&b' '))]);
+    }
+
+    // `nick[*]=<+|->user@host` for each nick that's taken: * for opers, - for away.
+    fn userhost(&mut self, args: &[IRCString]) {
+        let me = self.my_nick();
+        let mut replies = vec![];
+        for target in args.iter().take(USERHOST_NICKS) {
+            let found = self.directory.user_by_nick(target)
+                .and_then(|u| Some((self.directory.user_get_nick(u)?, self.directory.user_get_profile(u)?)));
+            let (nick, profile) = match found {
+                Some(f) => f,
+                None => continue
+            };
+            let mut reply = nick.bytes;
+            if profile.oper { reply.push(b'*'); }
+            reply.push(b'=');
+            reply.push(if profile.away.is_some() { b'-' } else { b'+' });
+            reply.extend(&profile.user.bytes);
+            reply.push(b'@');
+            reply.extend(&profile.host.bytes);
+            replies.push(reply);
+        }
+        self.send_from_server("302", vec![me, IRCString::new(replies.join(&b' '))]);
+    }
+
+    // WHO for a room's members or for one nick (no wildcards). With `%fields`
+    // (and maybe `,token`), it's WHOX: 354s with just those fields.
+    fn who(&mut self, mask: IRCString, options: Option<&IRCString>) {
+        let me = self.my_nick();
+        let (room_name, users) = if self.is_channel_name(&mask) {
+            let room = self.directory.room_by_name(&mask)
+                .and_then(|r| Some((self.directory.room_get_name(r)?, self.directory.room_get_snapshot(r)?)));
+            match room {
+                Some((name, snapshot)) => (name, snapshot.members.to_vec()),
+                None => (mask.clone(), vec![]),
+            }
+        } else {
+            (IRCString::from("*"), self.directory.user_by_nick(&mask).into_iter().collect())
+        };
+
+        let whox = options.and_then(|o| o.bytes.strip_prefix(b"%")).map(|o| {
+            match o.iter().position(|b| *b == b',') {
+                Some(comma) => (o[..comma].to_vec(), IRCString::new(o[comma + 1..].to_vec())),
+                None => (o.to_vec(), IRCString::from("0")),
+            }
+        });
+
+        let server = self.server_name();
+        for user in users {
+            let (nick, profile) = match (self.directory.user_get_nick(user), self.directory.user_get_profile(user)) {
+                (Some(n), Some(p)) => (n, p),
+                _ => continue  // (on their way out)
+            };
+            let mut flags = if profile.away.is_some() { b"G".to_vec() } else { b"H".to_vec() };
+            if profile.oper { flags.push(b'*'); }
+            let flags = IRCString::new(flags);
+
+            let args = match &whox {
+                None => {
+                    let mut hops_realname = b"0 ".to_vec();
+                    hops_realname.extend(&profile.realname.bytes);
+                    vec![
+                        me.clone(), room_name.clone(), profile.user.clone(), profile.host.clone(),
+                        server.clone(), nick, flags, IRCString::new(hops_realname),
+                    ]
+                }
+                Some((fields, token)) => {
+                    let mut args = vec![me.clone()];
+                    // always in this order, whatever order they were asked for in
+                    for field in b"tcuihsnfdlaor" {
+                        if !fields.contains(field) { continue }
+                        args.push(match field {
+                            b't' => token.clone(),
+                            b'c' => room_name.clone(),
+                            b'u' => profile.user.clone(),
+                            b'i' | b'h' => profile.host.clone(),
+                            b's' => server.clone(),
+                            b'n' => nick.clone(),
+                            b'f' => flags.clone(),
+                            b'd' | b'l' => IRCString::from("0"),
+                            b'a' => profile.account.clone().unwrap_or_else(|| IRCString::from("0")),
+                            b'o' => IRCString::from("n/a"),
+                            _ => profile.realname.clone(),
+                        });
+                    }
+                    args
+                }
+            };
+            let numeric = if whox.is_some() { "354" } else { "352" };
+            self.send_from_server(numeric, args);
+        }
+        self.send_from_server("315", vec![me, mask, IRCString::from("End of /WHO list")]);
+    }
+
+    fn monitor(&mut self, mut sub: IRCString, targets: Option<&IRCString>) {
+        sub.upper_inplace();
+        let targets: Vec<IRCString> = targets.map_or(vec![], |t| {
+            t.bytes.split(|b| *b == b',').filter(|n| !n.is_empty()).map(|n| IRCString::new(n.to_vec())).collect()
+        });
+        let casemapping = self.directory.config().casemapping;
+
+        match sub.bytes.as_slice() {
+            b"+" => {
+                let mut added = vec![];
+                for (i, nick) in targets.iter().enumerate() {
+                    let key = casemapping.fold(nick);
+                    if !self.monitoring.contains_key(&key) {
+                        if self.monitoring.len() >= self.class.monitor {
+                            let rest = targets[i..].iter().map(|t| &t.bytes[..]).collect::<Vec<_>>().join(&b',');
+                            let me = self.my_nick();
+                            let limit = IRCString::from(self.class.monitor.to_string().as_str());
+                            self.send_from_server("734", vec![me, limit, IRCString::new(rest), IRCString::from("Monitor list is full")]);
+                            break
                         }
-                        None => panic!("TODO")
+                        self.directory.user_watch(self.id, nick);
+                        self.monitoring.insert(key, nick.clone());
                     }
+                    added.push(nick.clone());
                 }
+                self.send_monitor_status(added);
+            }
+            b"-" => {
+                for nick in targets {
+                    if self.monitoring.remove(&casemapping.fold(&nick)).is_some() {
+                        self.directory.user_unwatch(self.id, &nick);
+                    }
+                }
+            }
+            b"C" => { self.unmonitor_all() }
+            b"L" => {
+                let me = self.my_nick();
+                let list = self.monitoring.values().cloned().collect();
+                self.send(parse::dump_packed(Command {
+                    tags: vec![],
+                    pfx: Some(self.server_name()),
+                    cmd: IRCString::from("732"),
+                    args: vec![me.clone()],
+                }, list, Packing::Commas, 0.0));
+                self.send_from_server("733", vec![me, IRCString::from("End of MONITOR list")]);
             }
-            _ => { panic!("TODO") }
+            b"S" => {
+                let all = self.monitoring.values().cloned().collect();
+                self.send_monitor_status(all);
+            }
+            _ => {}
+        }
+    }
+
+    fn unmonitor_all(&mut self) {
+        for (_, nick) in self.monitoring.drain() {
+            self.directory.user_unwatch(self.id, &nick);
+        }
+    }
+
+    // RPL_MONONLINE for whichever of `nicks` are taken, and RPL_MONOFFLINE for the rest.
+    fn send_monitor_status(&mut self, nicks: Vec<IRCString>) {
+        let (mut online, mut offline) = (vec![], vec![]);
+        for nick in nicks {
+            match self.directory.user_by_nick(&nick) {
+                Some(user) => online.push(self.prefix_of(user, nick)),
+                None => offline.push(nick),
+            }
+        }
+        for (numeric, list) in [("730", online), ("731", offline)] {
+            if list.is_empty() { continue }
+            let me = self.my_nick();
+            self.send(parse::dump_packed(Command {
+                tags: vec![],
+                pfx: Some(self.server_name()),
+                cmd: IRCString::from(numeric),
+                args: vec![me],
+            }, list, Packing::Commas, 0.0));
+        }
+    }
+
+    // nick!user@host for someone else, or just the nick if that's all we know.
+    fn prefix_of(&self, user: UserID, mut nick: IRCString) -> IRCString {
+        if let Some(p) = self.directory.user_get_profile(user) {
+            nick.bytes.push(b'!');
+            nick.bytes.extend(&p.user.bytes);
+            nick.bytes.push(b'@');
+            nick.bytes.extend(&p.host.bytes);
+        }
+        nick
+    }
+
+    // echo-message: hand the sender back the very message everyone else got,
+    // so the msgid and time match theirs. (Only once it actually went out.)
+    fn echo(&mut self, relayed: &Relayed) {
+        if self.caps.has(Cap::EchoMessage) {
+            self.send_relayed(relayed);
         }
     }
 
+    fn chathistory(&mut self, cmd: &Command) {
+        let fail = |this: &mut Self, code: &str, mut args: Vec<IRCString>, text: &str| {
+            args.insert(0, IRCString::from(code));
+            args.insert(0, IRCString::from("CHATHISTORY"));
+            args.push(IRCString::from(text));
+            this.send_from_server("FAIL", args);
+        };
+        let mut sub = match cmd.args.first() {
+            Some(s) => s.clone(),
+            None => return fail(self, "NEED_MORE_PARAMS", vec![], "Missing parameters")
+        };
+        sub.upper_inplace();
+        let max = self.directory.config().history.max_query;
+        let limit = |n: &IRCString| std::str::from_utf8(&n.bytes).ok()
+            .and_then(|n| n.parse::<usize>().ok())
+            .filter(|n| *n > 0)
+            .map(|n| n.min(max));
+        let point = |p: &IRCString| Point::parse(&p.bytes);
+
+        let parsed = match (sub.bytes.as_slice(), &cmd.args[1..]) {
+            (b"TARGETS", [from, to, n]) => {
+                match (point(from), point(to), limit(n)) {
+                    (Some(Point::Time(from)), Some(Point::Time(to)), Some(n)) => {
+                        return self.chathistory_targets(from, to, n)
+                    }
+                    _ => None
+                }
+            }
+            (b"LATEST", [target, p, n]) if p.bytes == b"*" => limit(n).map(|n| (target, Query::Latest(None), n)),
+            (b"LATEST", [target, p, n]) => point(p).zip(limit(n)).map(|(p, n)| (target, Query::Latest(Some(p)), n)),
+            (b"BEFORE", [target, p, n]) => point(p).zip(limit(n)).map(|(p, n)| (target, Query::Before(p), n)),
+            (b"AFTER", [target, p, n]) => point(p).zip(limit(n)).map(|(p, n)| (target, Query::After(p), n)),
+            (b"AROUND", [target, p, n]) => point(p).zip(limit(n)).map(|(p, n)| (target, Query::Around(p), n)),
+            (b"BETWEEN", [target, a, b, n]) => match (point(a), point(b), limit(n)) {
+                (Some(a), Some(b), Some(n)) => Some((target, Query::Between(a, b), n)),
+                _ => None
+            },
+            (b"TARGETS" | b"LATEST" | b"BEFORE" | b"AFTER" | b"AROUND" | b"BETWEEN", _) => {
+                return fail(self, "NEED_MORE_PARAMS", vec![sub], "Missing parameters")
+            }
+            _ => return fail(self, "UNKNOWN_COMMAND", vec![sub], "Unknown command")
+        };
+        let (target, query, n) = match parsed {
+            Some(p) => p,
+            None => return fail(self, "INVALID_PARAMS", vec![sub], "Invalid parameters")
+        };
+
+        // only rooms they're in, and their own conversations
+        let me = self.history_owner();
+        let name = if self.is_channel_name(target) {
+            match self.directory.room_by_name(target).and_then(|r| self.memberships.get(&r)) {
+                Some(m) => LogName::Room(&m.name),
+                None => return fail(self, "INVALID_TARGET", vec![sub, target.clone()], "Messages could not be retrieved")
+            }
+        } else {
+            LogName::Conversation(&me, target)
+        };
+
+        let caps = self.caps;
+        let msgs = self.directory.history().query(name, &query, n).into_iter()
+            .filter(|m| m.visible_to(caps))
+            .map(|m| MessageOut { deadline: Instant::now() + Duration::from_secs(1), data: m.wire(caps) })
+            .collect();
+        self.send_batch("chathistory", vec![target.clone()], msgs);
+    }
+
+    fn chathistory_targets(&mut self, from: SystemTime, to: SystemTime, n: usize) {
+        let rooms: Vec<IRCString> = self.memberships.values().map(|m| m.name.clone()).collect();
+        let found = self.directory.history().targets(&self.history_owner(), &rooms, from, to, n);
+        let msgs = found.into_iter().map(|(target, latest)| parse::dump(Command {
+            tags: vec![],
+            pfx: Some(self.server_name()),
+            cmd: IRCString::from("CHATHISTORY"),
+            args: vec![IRCString::from("TARGETS"), target, tags::server_time(latest)],
+        }, 0.0)).collect();
+        self.send_batch("draft/chathistory-targets", vec![], msgs);
+    }
+
+    // Join-time history, for clients that won't ask for it with CHATHISTORY.
+    // Without batches, there's no telling it apart from anything new, so it
+    // comes as NOTICEs from us saying who said what when.
+    fn send_playback(&mut self, room_name: IRCString, history: Vec<Arc<Relayed>>) {
+        if history.is_empty() || self.caps.has(Cap::ChatHistory) { return }
+
+        if self.caps.has(Cap::Batch) {
+            let caps = self.caps;
+            let msgs = history.iter()
+                .map(|m| MessageOut { deadline: Instant::now() + Duration::from_secs(1), data: m.wire(caps) })
+                .collect();
+            return self.send_batch("chathistory", vec![room_name], msgs)
+        }
+        for m in history {
+            let text = playback_line(&m);
+            self.send_from_server("NOTICE", vec![room_name.clone(), text]);
+        }
+    }
+
+    fn send_names(&mut self, room_name: IRCString, nicks: Vec<IRCString>) {
+        let nick = self.my_nick();
+        let msg = parse::dump_packed(Command {
+            tags: vec![],
+            pfx: Some(self.server_name()),
+            cmd: IRCString::from("353"),
+            args: vec![nick.clone(), IRCString::from("="), room_name.clone()],
+        }, nicks, Packing::Trailing, 0.0);
+        self.send(msg);
+        self.send_from_server("366", vec![nick, room_name, IRCString::from("End of /NAMES list")]);
+    }
+
+    fn send_relayed(&mut self, relayed: &Relayed) {
+        if !relayed.visible_to(self.caps) { return }
+        self.send(MessageOut { 
+            deadline: Instant::now() + Duration::from_secs_f32(0.5), 
+            data: relayed.wire(self.caps) 
+        });
+    }
+
     async fn handle_server(&mut self, msg: ToUser) {
         match msg {
-            ToUser::User { nick, message } => {
+            ToUser::Directory { message } => {
+                let nick = match &message {
+                    D2U::Online { nick, .. } | D2U::Offline { nick } => nick,
+                };
+                // (unless we've stopped watching since)
+                if !self.monitoring.contains_key(&self.directory.config().casemapping.fold(nick)) { return }
+                let (numeric, target) = match message {
+                    D2U::Online { user, nick } => ("730", self.prefix_of(user, nick)),
+                    D2U::Offline { nick } => ("731", nick),
+                };
+                let me = self.my_nick();
+                self.send_from_server(numeric, vec![me, target]);
+            }
+            ToUser::User { message } => {
+                match message {
+                    U2U::Message { relayed } => { self.send_relayed(&relayed); }
+                }
+            }
+            ToUser::Room { room_id, message } => {
+                let room_name = match self.memberships.get(&room_id) {
+                    Some(m) => m.name.clone(),
+                    None => return  // we already left
+                };
                 match message {
-                    U2U::Privmsg { message } => {
-                        let _ = self.sock.send.send(parse::dump(Command { 
-                            pfx: Some(nick),
-                            cmd: IRCString::new(b"PRIVMSG".to_vec()),
-                            args: vec![self.my_nick(), message]
-                        }, 0.5));
+                    R2U::Lagged { missed, replayed } => {
+                        let nick = self.my_nick();
+                        let text = format!(
+                            "*** You fell behind in {}: {} messages were skipped, replaying the last {}",
+                            room_name, missed, replayed
+                        );
+                        self.send_from_server("NOTICE", vec![nick, IRCString::from(text.as_str())]);
+                    }
+                    R2U::Desynced => {
+                        self.disconnect(&format!("Fell too far behind in {}", room_name));
+                    }
+                    R2U::Message { user, relayed } => {
+                        if user == self.id { return }
+                        self.send_relayed(&relayed);
+                    }
+                    R2U::Joined { ticket, relayed, nicks, history } => {
+                        // (from a Join we've since taken back with a PART)
+                        let response = match self.memberships.get_mut(&room_id) {
+                            Some(m) if m.ticket == ticket => { m.pending_join = None; m.response.take() }
+                            _ => return
+                        };
+                        self.responses.resume(response);
+                        self.send_relayed(&relayed);
+                        self.send_names(room_name.clone(), nicks);
+                        self.send_playback(room_name, history);
+                        self.responses.release(response);
+                        self.end_response();
+                    }
+                    R2U::Join { user, relayed } => {
+                        // we got ours as Joined
+                        if user == self.id { return }
+                        self.send_relayed(&relayed);
+
+                        let profile = self.directory.user_get_profile(user);
+                        let away = profile.as_ref().and_then(|p| p.away.clone());
+                        if let (Some(away), true) = (away, self.caps.has(Cap::AwayNotify)) {
+                            let tags = profile.and_then(|p| p.account.clone()).map(|a| Tag::new("account", a)).into_iter().collect();
+                            let command = Command { tags, pfx: relayed.command.pfx.clone(), cmd: IRCString::from("AWAY"), args: vec![away] };
+                            self.send_relayed(&Relayed::new(command, Instant::now()));
+                        }
+                    }
+                    R2U::Away { user, relayed } => {
+                        if user == self.id { return }
+                        self.send_notify(Cap::AwayNotify, user, &relayed);
+                    }
+                    R2U::Account { user, relayed } => {
+                        if user == self.id { return }
+                        self.send_notify(Cap::AccountNotify, user, &relayed);
+                    }
+                    R2U::Nick { user, relayed } => {
+                        if user == self.id || !self.first_copy(user, &relayed) { return }
+                        self.send_relayed(&relayed);
+                    }
+                    R2U::Part { user, relayed } => {
+                        if user == self.id { return }
+                        self.send_relayed(&relayed);
+                    }
+                    R2U::Closed { reason } => {
+                        if let Some(m) = self.memberships.remove(&room_id) {
+                            self.directory.user_leave_room(self.id, room_id);
+                            if let Some(r) = self.responses.release(m.response) { self.send_response(r) }
+                            let command = Command { 
+                                tags: vec![], 
+                                pfx: Some(self.my_prefix()), 
+                                cmd: IRCString::from("PART"), 
+                                args: vec![m.name, IRCString::from(reason)] 
+                            };
+                            self.send_relayed(&Relayed::new(command, Instant::now()));
+                        }
+                    }
+                    R2U::Gone => {
+                        // the JOIN's answer, if it was labeled, comes from the retry instead
+                        let response = self.memberships.remove(&room_id).and_then(|m| m.response);
+                        self.directory.user_leave_room(self.id, room_id);
+                        self.responses.resume(response);
+                        self.join(room_name).await;
+                        self.responses.release(response);
+                        self.end_response();
                     }
                 }
             }
-            _ => { panic!("TODO") }
         }
     }
 
-    pub fn send_room(&mut self, room: RoomID, msg: U2R) {
-        panic!("TODO");
+    // For joining and leaving, which can't be dropped. Rooms never wait on
+    // us, so waiting on them can't deadlock.
+    pub async fn send_room(&mut self, room: RoomID, msg: U2R) {
+        if let Some(m) = self.memberships.get(&room) {
+            // if the room's gone, there's nobody left to hear it anyway
+            let _ = m.mailbox.send(msg).await;
+        }
+    }
+
+    // For everything else. Returns false if the room's too busy to take it.
+    pub fn try_send_room(&mut self, room: RoomID, msg: U2R) -> bool {
+        match self.memberships.get(&room).map(|m| m.mailbox.try_send(msg)) {
+            Some(Err(TrySendError::Full(_))) => false,
+            // if the room's gone, there's nobody left to hear it anyway
+            _ => true
+        }
+    }
+
+    // Someone's mailbox was full, so we dropped what this client sent them.
+    fn target_busy(&mut self, cmd: IRCString, target: IRCString) {
+        self.send_from_server("FAIL", vec![
+            cmd, IRCString::from("TEMPORARILY_UNAVAILABLE"), target,
+            IRCString::from("Target is too busy, message dropped"),
+        ]);
     }
 
     pub async fn kill(&mut self) {
         let rooms: Vec<RoomID> = self.memberships.keys().cloned().collect();  // TODO: Avoid this
         for room_id in rooms {
-            self.send_room(room_id, U2R::Part { user: self.id })
+            let name = self.memberships[&room_id].name.clone();
+            let command = Command { tags: vec![], pfx: Some(self.my_prefix()), cmd: IRCString::from("PART"), args: vec![name] };
+            let relayed = Relayed::new(command, Instant::now());
+            self.send_room(room_id, U2R::Part { user: self.id, relayed }).await
         }
         self.memberships.clear();
+        self.unmonitor_all();
         self.done = true;
     }
 }
+
+// `[2011-10-19 16:40:51] <nick> text`, or `-nick-` for a NOTICE, or `* nick does` for an ACTION.
+fn playback_line(m: &Relayed) -> IRCString {
+    let time = tags::server_time(m.time).bytes;
+    let pfx = m.command.pfx.as_ref().map_or(&b""[..], |p| &p.bytes);
+    let nick = &pfx[..pfx.iter().position(|b| *b == b'!').unwrap_or(pfx.len())];
+    let text = m.command.args.last().map_or(&b""[..], |t| &t.bytes);
+
+    let mut out = b"[".to_vec();
+    out.extend(&time[..10]);
+    out.push(b' ');
+    out.extend(&time[11..19]);
+    out.extend(b"] ");
+    match text.strip_prefix(b"\x01ACTION ") {
+        Some(action) => {
+            out.extend(b"* ");
+            out.extend(nick);
+            out.push(b' ');
+            out.extend(action.strip_suffix(b"\x01").unwrap_or(action));
+        }
+        None => {
+            let (open, close) = if m.command.cmd.bytes == b"NOTICE" { (b'-', b'-') } else { (b'<', b'>') };
+            out.push(open);
+            out.extend(nick);
+            out.push(close);
+            out.push(b' ');
+            out.extend(text);
+        }
+    }
+    IRCString::new(out)
+}
+
 impl UserIDCard {
     pub(crate) fn is_complete(&self) -> bool {
         // we don't care about realname
-        return self.nick.is_some() && self.user.is_some()
+        self.nick.is_some() && self.user.is_some()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    // Two users PRIVMSG each other as fast as they can. If user tasks waited on
+    // each other's mailboxes, they'd each end up stuck on the other's full one.
+
+    use std::{sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}}, time::Duration};
+
+    use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines}, net::{TcpListener, TcpStream, tcp::{OwnedReadHalf, OwnedWriteHalf}}};
+
+    use crate::{config::{AccountBlock, Config, ConnectionClass, FloodControl}, directory::DirectoryRoot, encoding::InputEncoding, sock::Sock};
+
+    const MESSAGES: usize = 5_000;
+
+    #[derive(Default)]
+    struct Tally {
+        received: AtomicUsize,
+        dropped: AtomicUsize,
+        ponged: AtomicBool,
+    }
+
+    // Sign on, and wait for the welcome.
+    async fn register(client: TcpStream, nick: &str) -> (Lines<BufReader<OwnedReadHalf>>, OwnedWriteHalf) {
+        let (r, mut w) = client.into_split();
+        w.write_all(format!("NICK {}\r\nUSER {} 0 * :{}\r\n", nick, nick, nick).as_bytes()).await.unwrap();
+        let mut lines = BufReader::new(r).lines();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            if line.contains(" 001 ") { break }
+        }
+        (lines, w)
+    }
+
+    fn flood(client: (Lines<BufReader<OwnedReadHalf>>, OwnedWriteHalf), to: &'static str, tally: Arc<Tally>) {
+        let (mut lines, mut w) = client;
+        tokio::spawn(async move {
+            let from_them = format!(":{}!", to);
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line.starts_with(&from_them) && line.contains(" PRIVMSG ") { tally.received.fetch_add(1, Ordering::Relaxed); }
+                if line.contains(" FAIL PRIVMSG TEMPORARILY_UNAVAILABLE ") { tally.dropped.fetch_add(1, Ordering::Relaxed); }
+                if line.contains(" PONG ") { tally.ponged.store(true, Ordering::Relaxed); }
+            }
+        });
+        tokio::spawn(async move {
+            let mut out = String::new();
+            for i in 0..MESSAGES { out += &format!("PRIVMSG {} :{}\r\n", to, i); }
+            out += "PING :done\r\n";
+            w.write_all(out.as_bytes()).await.unwrap();
+            // keep the connection open until the test's over
+            std::future::pending::<()>().await;
+        });
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn mutual_privmsg_flood_does_not_deadlock() {
+        let mut config = Config::default();
+        // small mailboxes, so they fill up
+        let class = Arc::new(ConnectionClass {
+            mailbox: 4,
+            flood: FloodControl { exempt: true, ..FloodControl::default() },
+            ..ConnectionClass::default()
+        });
+        config.classes.insert("default".to_string(), class.clone());
+        let root = DirectoryRoot::new(Arc::new(config));
+        let directory = root.share();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut clients = vec![];
+        for _ in 0..2 {
+            let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (socket, addr) = listener.accept().await.unwrap();
+            directory.user_create(Sock::watch(socket, addr, class.sendq), class.clone(), InputEncoding::Bytes);
+            clients.push(client);
+        }
+
+        let (bob, alice) = (clients.pop().unwrap(), clients.pop().unwrap());
+        let (alice, bob) = (register(alice, "alice").await, register(bob, "bob").await);
+
+        let (a, b) = (Arc::new(Tally::default()), Arc::new(Tally::default()));
+        flood(alice, "bob", a.clone());
+        flood(bob, "alice", b.clone());
+
+        // everything either arrived, or its sender was told it was dropped
+        let settled = async {
+            loop {
+                let (ar, ad) = (a.received.load(Ordering::Relaxed), a.dropped.load(Ordering::Relaxed));
+                let (br, bd) = (b.received.load(Ordering::Relaxed), b.dropped.load(Ordering::Relaxed));
+                if a.ponged.load(Ordering::Relaxed) && b.ponged.load(Ordering::Relaxed) && ar + bd >= MESSAGES && br + ad >= MESSAGES {
+                    return (ar, ad, br, bd)
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        match tokio::time::timeout(Duration::from_secs(30), settled).await {
+            Ok((ar, ad, br, bd)) => {
+                println!("alice got {} ({} of hers dropped), bob got {} ({} of his dropped)", ar, ad, br, bd);
+                assert!(ar + bd == MESSAGES && br + ad == MESSAGES);
+            }
+            Err(_) => panic!(
+                "stalled: alice got {}, {} dropped; bob got {}, {} dropped",
+                a.received.load(Ordering::Relaxed), a.dropped.load(Ordering::Relaxed),
+                b.received.load(Ordering::Relaxed), b.dropped.load(Ordering::Relaxed),
+            )
+        }
+    }
+
+    // Read up to the first line that has `needle` in it, and return everything up to there.
+    async fn expect(lines: &mut Lines<BufReader<OwnedReadHalf>>, needle: &str) -> Vec<String> {
+        let mut seen = vec![];
+        let read = async {
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let done = line.contains(needle);
+                seen.push(line);
+                if done { return }
+            }
+        };
+        if tokio::time::timeout(Duration::from_secs(10), read).await.is_err() { panic!("never saw {:?} in {:?}", needle, seen) }
+        seen
+    }
+
+    // Someone logs in while three others, each with one of the account caps
+    // (and one with none), watch from a room they're in.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn login_shows_up_for_each_account_cap() {
+        let mut config = Config::default();
+        config.accounts.push(AccountBlock { name: "alice".to_string(), password: "hunter2".to_string() });
+        let class = config.classes["default"].clone();
+        let root = DirectoryRoot::new(Arc::new(config));
+        let directory = root.share();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let connect = async |nick: &str, cap: &str| {
+            let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (socket, addr) = listener.accept().await.unwrap();
+            directory.user_create(Sock::watch(socket, addr, class.sendq), class.clone(), InputEncoding::Bytes);
+            let (r, mut w) = client.into_split();
+            let req = if cap.is_empty() { String::new() } else { format!("CAP REQ :{}\r\n", cap) };
+            w.write_all(format!("{}NICK {}\r\nUSER {} 0 * :{}\r\nCAP END\r\nJOIN #r\r\n", req, nick, nick, nick).as_bytes()).await.unwrap();
+            let mut lines = BufReader::new(r).lines();
+            expect(&mut lines, " 366 ").await;
+            (lines, w)
+        };
+
+        let caps = ["", "account-notify", "extended-join", "account-tag"];
+        let mut watchers = vec![];
+        for (i, cap) in caps.iter().enumerate() { watchers.push(connect(&format!("w{}", i), cap).await); }
+
+        let (mut lines, mut w) = connect("alice", "").await;
+        w.write_all(b"AUTHENTICATE PLAIN\r\n").await.unwrap();
+        expect(&mut lines, "AUTHENTICATE +").await;
+        // \0alice\0hunter2
+        w.write_all(b"AUTHENTICATE AGFsaWNlAGh1bnRlcjI=\r\n").await.unwrap();
+        let said = expect(&mut lines, " 903 ").await;
+        assert!(said.iter().any(|l| l.contains(" 900 alice alice!alice@127.0.0.1 alice ")), "{:?}", said);
+        w.write_all(b"PRIVMSG #r :hi\r\nPART #r\r\nJOIN #r\r\n").await.unwrap();
+
+        let mut seen = vec![];
+        for (lines, _) in watchers.iter_mut() {
+            // (through their JOIN before they logged in, and the one after)
+            let mut heard = expect(lines, ":alice!alice@127.0.0.1 JOIN").await;
+            heard.extend(expect(lines, ":alice!alice@127.0.0.1 JOIN").await);
+            seen.push(heard.into_iter().filter(|l| l.contains(":alice!")).collect::<Vec<_>>());
+        }
+        let expected = [
+            vec![
+                ":alice!alice@127.0.0.1 JOIN #r",
+                ":alice!alice@127.0.0.1 PRIVMSG #r hi",
+                ":alice!alice@127.0.0.1 PART #r",
+                ":alice!alice@127.0.0.1 JOIN #r",
+            ],
+            vec![
+                ":alice!alice@127.0.0.1 JOIN #r",
+                ":alice!alice@127.0.0.1 ACCOUNT alice",
+                ":alice!alice@127.0.0.1 PRIVMSG #r hi",
+                ":alice!alice@127.0.0.1 PART #r",
+                ":alice!alice@127.0.0.1 JOIN #r",
+            ],
+            vec![
+                ":alice!alice@127.0.0.1 JOIN #r * alice",
+                ":alice!alice@127.0.0.1 PRIVMSG #r hi",
+                ":alice!alice@127.0.0.1 PART #r",
+                ":alice!alice@127.0.0.1 JOIN #r alice alice",
+            ],
+            vec![
+                ":alice!alice@127.0.0.1 JOIN #r",
+                "@account=alice :alice!alice@127.0.0.1 PRIVMSG #r hi",
+                "@account=alice :alice!alice@127.0.0.1 PART #r",
+                "@account=alice :alice!alice@127.0.0.1 JOIN #r",
+            ],
+        ];
+        for ((cap, seen), expected) in caps.iter().zip(seen).zip(expected) {
+            assert_eq!(seen, expected, "with {:?}", cap);
+        }
+    }
+}
@@ -1,9 +1,8 @@
-use std::{collections::HashMap, ops::Not};
+use std::sync::Arc;
 
-use slotmap::{SlotMap, Key};
-use tokio::{sync::{broadcast, mpsc::{UnboundedReceiver, UnboundedSender}}, net::TcpListener};
+use tokio::{net::TcpListener, signal::unix::{signal, SignalKind}, sync::oneshot};
 
-use crate::{room::{Room, RoomID}, user::{User, UserID}, sock::Sock, directory::{Directory, DirectoryRoot}};
+use crate::{sock::Sock, directory::{Directory, DirectoryRoot}, config::{Config, ConnectionClass}, cancel::Cancel, encoding::InputEncoding};
 
 // use crate::{user_conn::{MessageOut, MessageIn}, subscriptions::{Subscriptions, Notification}};
 
@@ -27,28 +26,85 @@ impl<K: Key, V> DSlotMap<K, V> {
 */
 
 pub struct World {
+    config: Arc<Config>,
     directory_root: DirectoryRoot,
 }
 
 impl World {
-    pub fn new() -> Self {
-        World {
-            directory_root: DirectoryRoot::new()
+    pub fn new(config: Config) -> Self {
+        let config = Arc::new(config);
+        let directory_root = DirectoryRoot::new(config.clone());
+        if let Some(path) = &config.history.path {
+            match directory_root.history().load(path) {
+                Ok(0) => {}
+                Ok(skipped) => eprintln!("skipped {} unreadable lines of history in {}", skipped, path.display()),
+                Err(e) => eprintln!("couldn't load history from {}: {}", path.display(), e),
+            }
         }
+        World { directory_root, config }
     }
 
     fn directory(&self) -> Directory {
         self.directory_root.share()
     }
 
-    pub async fn main_loop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let listener = TcpListener::bind("127.0.0.1:6667").await?;
+    pub async fn main_loop(self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut accepting = vec![];
+        let mut stop_accepting = vec![];
+        for l in self.config.listeners.iter() {
+            let class = match self.config.classes.get(&l.class) {
+                Some(c) => c.clone(),
+                None => return Err(format!("listener {} uses unknown class {:?}", l.addr, l.class).into())
+            };
+            let listener = TcpListener::bind(l.addr).await?;
+            let (cancel, receive_cancel) = Cancel::new();
+            stop_accepting.push(cancel);
+            accepting.push(tokio::spawn(World::accept_loop(listener, class, l.encoding, self.directory(), receive_cancel)));
+        }
+
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        let run = async move {
+            for a in accepting { a.await??; }
+            Ok::<(), Box<dyn std::error::Error>>(())
+        };
+        tokio::select! {
+            r = run => { r?; }
+            _ = interrupt.recv() => { println!("got SIGINT, shutting down"); }
+            _ = terminate.recv() => { println!("got SIGTERM, shutting down"); }
+        }
+
+        drop(stop_accepting);
+        self.shutdown().await;
+        Ok(())
+    }
+
+    async fn shutdown(self) {
+        // let everyone go, then save what they said
+        let timeout = self.config.shutdown_timeout;
+        let history = self.directory_root.history();
+        if !self.directory_root.shutdown(timeout).await {
+            eprintln!("some connections didn't close within {:?}", timeout);
+        }
+        if let Some(path) = &self.config.history.path {
+            if let Err(e) = history.save(path) {
+                eprintln!("couldn't save history to {}: {}", path.display(), e);
+            }
+        }
+    }
 
+    async fn accept_loop(
+        listener: TcpListener, class: Arc<ConnectionClass>, encoding: InputEncoding, 
+        directory: Directory, mut cancel: oneshot::Receiver<()>
+    ) -> std::io::Result<()> {
         loop {
-            let (socket, addr) = listener.accept().await?;
+            let (socket, addr) = tokio::select! {
+                _ = &mut cancel => { return Ok(()) }
+                x = listener.accept() => x?
+            };
             println!("accepted!");
 
-            self.directory().user_create(Sock::watch(socket, addr));
+            directory.user_create(Sock::watch(socket, addr, class.sendq), class.clone(), encoding);
             println!("user created!");
         }
     }